@@ -5,7 +5,7 @@ use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::io::AsyncWriteExt;
 use tokio_rustls_acme::caches::DirCache;
-use tokio_rustls_acme::{AcmeAcceptor, AcmeConfig};
+use tokio_rustls_acme::{AcceptError, AcceptedOrValidation, AcmeAcceptor, AcmeConfig};
 use tokio_stream::StreamExt;
 
 #[derive(Parser, Debug)]
@@ -68,9 +68,13 @@ async fn serve(acceptor: AcmeAcceptor, rustls_config: Arc<ServerConfig>, port: u
         let accept_future = acceptor.accept(tcp);
 
         tokio::spawn(async move {
-            match accept_future.await.unwrap() {
-                None => log::info!("received TLS-ALPN-01 validation request"),
-                Some(start_handshake) => {
+            match accept_future.await {
+                Ok(AcceptedOrValidation::Validation) => {
+                    log::debug!("received TLS-ALPN-01 validation request")
+                }
+                Err(AcceptError::ClientHello(err)) => log::warn!("invalid ClientHello: {}", err),
+                Err(AcceptError::Io(err)) => log::error!("accept failed: {}", err),
+                Ok(AcceptedOrValidation::Accepted(start_handshake)) => {
                     let mut tls = start_handshake.into_stream(rustls_config).await.unwrap();
                     tls.write_all(HELLO).await.unwrap();
                     tls.shutdown().await.unwrap();
@@ -0,0 +1,93 @@
+//! End-to-end issuance against a local [Pebble](https://github.com/letsencrypt/pebble) server.
+//!
+//! Pebble must resolve the domain to this host, e.g. through `pebble-challtestsrv`, and validate
+//! TLS-ALPN-01 on the port given with `--port`, 5001 by default. Exits with an error unless a
+//! new certificate is issued and deployed.
+
+use clap::Parser;
+use rustls::pki_types::CertificateDer;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio_rustls_acme::{AcceptedOrValidation, AcmeConfig, EventOk};
+use tokio_stream::StreamExt;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Domain to order a certificate for
+    #[clap(short, default_value = "example.test")]
+    domain: String,
+
+    /// Pebble directory URL
+    #[clap(long, default_value = "https://localhost:14000/dir")]
+    directory: String,
+
+    /// PEM file with the root certificate of the Pebble API, pebble.minica.pem
+    #[clap(long)]
+    root: PathBuf,
+
+    /// Port on which Pebble validates TLS-ALPN-01
+    #[clap(short, long, default_value = "5001")]
+    port: u16,
+
+    /// Seconds after which the issuance is considered failed
+    #[clap(long, default_value = "60")]
+    timeout: u64,
+}
+
+#[tokio::main]
+async fn main() {
+    simple_logger::init_with_level(log::Level::Info).unwrap();
+    let args = Args::parse();
+
+    let pem = std::fs::read(&args.root).unwrap();
+    let mut config = AcmeConfig::new([&args.domain]).directory(&args.directory);
+    for root in pem::parse_many(pem).unwrap() {
+        config = config
+            .add_root_certificate(CertificateDer::from(root.into_contents()))
+            .unwrap();
+    }
+    let mut state = config.state();
+
+    let acceptor = state.acceptor();
+    let listener = tokio::net::TcpListener::bind((Ipv4Addr::UNSPECIFIED, args.port))
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let accept = acceptor.accept(tcp);
+            tokio::spawn(async move {
+                match accept.await {
+                    Ok(AcceptedOrValidation::Validation) => log::info!("answered validation"),
+                    Ok(AcceptedOrValidation::Accepted(_)) => log::warn!("unexpected connection"),
+                    Err(err) => log::error!("accept failed: {:?}", err),
+                }
+            });
+        }
+    });
+
+    let issuance = async {
+        loop {
+            match state.next().await.unwrap() {
+                Ok(EventOk::DeployedNewCert { certificate_url }) => break certificate_url,
+                Ok(ok) => log::info!("event: {:?}", ok),
+                Err(err) => panic!("issuance failed: {:?}", err),
+            }
+        }
+    };
+    let certificate_url = tokio::time::timeout(Duration::from_secs(args.timeout), issuance)
+        .await
+        .expect("issuance timed out");
+    let resolver = state.resolver();
+    let [not_before, not_after] = resolver.validity().expect("deployed cert is not parseable");
+    let names = resolver.subject_alt_names().unwrap();
+    assert!(names.contains(&args.domain), "cert is for {:?}", names);
+    log::info!(
+        "issued {} for {:?}, valid from {} until {}",
+        certificate_url,
+        names,
+        not_before,
+        not_after
+    );
+}
@@ -1,5 +1,7 @@
 use crate::acme::ACME_TLS_ALPN_NAME;
+use crate::crypto;
 use crate::ResolvesServerCertAcme;
+use rustls::crypto::CryptoProvider;
 use rustls::server::Acceptor;
 use rustls::ServerConfig;
 use std::future::Future;
@@ -7,19 +9,37 @@ use std::io;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_rustls::{Accept, LazyConfigAcceptor, StartHandshake};
 
+/// Accepts TLS connections and answers TLS-ALPN-01 validation requests.
+///
+/// Validation handshakes, i.e. those whose ClientHello offers only the `acme-tls/1` protocol, are
+/// completed with an internal [ServerConfig] that always advertises `acme-tls/1`. All other
+/// handshakes are handed back to the caller, who finishes them with their own [ServerConfig].
+/// That config is never used for validation and therefore does not need to list `acme-tls/1` in
+/// its ALPN protocols.
 #[derive(Clone)]
 pub struct AcmeAcceptor {
     config: Arc<ServerConfig>,
 }
 
 impl AcmeAcceptor {
-    pub(crate) fn new(resolver: Arc<ResolvesServerCertAcme>) -> Self {
-        let mut config = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_cert_resolver(resolver);
+    /// Create an acceptor answering validation requests with the challenge keys of `resolver`.
+    ///
+    /// Acceptors hold no state of their own, so several of them, also on different listeners,
+    /// can share one resolver. [AcmeState::acceptor](crate::AcmeState::acceptor) is a shorthand
+    /// for this.
+    pub fn new(resolver: Arc<ResolvesServerCertAcme>) -> Self {
+        Self::with_crypto_provider(resolver, crypto::default_provider())
+    }
+    /// Like [AcmeAcceptor::new], but answering validation requests with `provider`.
+    pub fn with_crypto_provider(
+        resolver: Arc<ResolvesServerCertAcme>,
+        provider: Arc<CryptoProvider>,
+    ) -> Self {
+        let mut config = crypto::server_config_builder(provider).with_cert_resolver(resolver);
         config.alpn_protocols.push(ACME_TLS_ALPN_NAME.to_vec());
         Self {
             config: Arc::new(config),
@@ -30,6 +50,47 @@ impl AcmeAcceptor {
     }
 }
 
+/// The outcome of a successful [AcmeAcceptor::accept].
+#[allow(clippy::large_enum_variant)]
+pub enum AcceptedOrValidation<IO> {
+    /// A regular handshake, to be finished by the caller.
+    Accepted(StartHandshake<IO>),
+    /// A TLS-ALPN-01 validation request, which has been answered already.
+    Validation,
+}
+
+#[derive(Error, Debug)]
+pub enum AcceptError {
+    #[error("io error: {0}")]
+    Io(io::Error),
+    #[error("invalid ClientHello: {0}")]
+    ClientHello(rustls::Error),
+}
+
+impl AcceptError {
+    fn client_hello(err: io::Error) -> Self {
+        // tokio-rustls reports TLS errors as `InvalidData`, wrapping the rustls error.
+        if err.kind() == io::ErrorKind::InvalidData
+            && err
+                .get_ref()
+                .is_some_and(|inner| inner.is::<rustls::Error>())
+        {
+            let inner = err.into_inner().unwrap().downcast::<rustls::Error>();
+            return AcceptError::ClientHello(*inner.unwrap());
+        }
+        AcceptError::Io(err)
+    }
+}
+
+impl From<AcceptError> for io::Error {
+    fn from(err: AcceptError) -> Self {
+        match err {
+            AcceptError::Io(err) => err,
+            AcceptError::ClientHello(err) => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
 pub struct AcmeAccept<IO: AsyncRead + AsyncWrite + Unpin> {
     acceptor: LazyConfigAcceptor<IO>,
     config: Arc<ServerConfig>,
@@ -47,14 +108,14 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> AcmeAccept<IO> {
 }
 
 impl<IO: AsyncRead + AsyncWrite + Unpin> Future for AcmeAccept<IO> {
-    type Output = io::Result<Option<StartHandshake<IO>>>;
+    type Output = Result<AcceptedOrValidation<IO>, AcceptError>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         loop {
             if let Some(validation_accept) = &mut self.validation_accept {
                 return match Pin::new(validation_accept).poll(cx) {
-                    Poll::Ready(Ok(_)) => Poll::Ready(Ok(None)),
-                    Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+                    Poll::Ready(Ok(_)) => Poll::Ready(Ok(AcceptedOrValidation::Validation)),
+                    Poll::Ready(Err(err)) => Poll::Ready(Err(AcceptError::Io(err))),
                     Poll::Pending => Poll::Pending,
                 };
             }
@@ -71,11 +132,89 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> Future for AcmeAccept<IO> {
                         self.validation_accept = Some(handshake.into_stream(self.config.clone()));
                         continue;
                     }
-                    Poll::Ready(Ok(Some(handshake)))
+                    Poll::Ready(Ok(AcceptedOrValidation::Accepted(handshake)))
                 }
-                Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+                Poll::Ready(Err(err)) => Poll::Ready(Err(AcceptError::client_hello(err))),
                 Poll::Pending => Poll::Pending,
             };
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto;
+    use crate::crypto::rustls_backend::sign::any_ecdsa_type;
+    use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName};
+    use rustls::sign::CertifiedKey;
+    use rustls::RootCertStore;
+    use std::convert::TryFrom;
+    use std::time::Duration;
+    use tokio_rustls::TlsConnector;
+
+    fn acceptor_with_auth_key() -> (AcmeAcceptor, CertificateDer<'static>) {
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let params = rcgen::CertificateParams::new(vec!["example.com".to_string()]).unwrap();
+        let cert = params.self_signed(&key_pair).unwrap();
+        let key = PrivatePkcs8KeyDer::from(key_pair.serialize_der()).into();
+        let key = any_ecdsa_type(&key).unwrap();
+        let resolver = ResolvesServerCertAcme::new(None);
+        resolver.set_auth_key(
+            "example.com".into(),
+            Arc::new(CertifiedKey::new(vec![cert.der().clone()], key)),
+        );
+        (AcmeAcceptor::new(resolver), cert.der().clone())
+    }
+
+    fn connector(cert: CertificateDer<'static>, alpn: &[u8]) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let mut client_config = crypto::client_config_builder(crypto::default_provider())
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![alpn.to_vec()];
+        TlsConnector::from(Arc::new(client_config))
+    }
+
+    #[tokio::test]
+    async fn validation_handshakes_advertise_acme_tls() {
+        let (acceptor, cert) = acceptor_with_auth_key();
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let accept = tokio::spawn(acceptor.accept(server));
+        let server_name = ServerName::try_from("example.com").unwrap();
+        let connect = connector(cert, ACME_TLS_ALPN_NAME).connect(server_name, client);
+        let tls = tokio::time::timeout(Duration::from_secs(10), connect)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(tls.get_ref().1.alpn_protocol(), Some(ACME_TLS_ALPN_NAME));
+        assert!(matches!(
+            accept.await.unwrap(),
+            Ok(AcceptedOrValidation::Validation)
+        ));
+    }
+
+    #[tokio::test]
+    async fn other_handshakes_are_handed_back() {
+        let (acceptor, cert) = acceptor_with_auth_key();
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let server_name = ServerName::try_from("example.com").unwrap();
+        let connect = connector(cert, b"h2").connect(server_name, client);
+        let _client = tokio::spawn(connect);
+        let handshake = tokio::time::timeout(Duration::from_secs(10), acceptor.accept(server))
+            .await
+            .unwrap();
+        let handshake = match handshake {
+            Ok(AcceptedOrValidation::Accepted(handshake)) => handshake,
+            _ => panic!("expected the handshake to be handed back"),
+        };
+        let alpn: Vec<_> = handshake
+            .client_hello()
+            .alpn()
+            .into_iter()
+            .flatten()
+            .collect();
+        assert_eq!(alpn, [b"h2"]);
+    }
+}
@@ -1,8 +1,7 @@
-use crate::{AcmeAccept, AcmeAcceptor};
+use crate::{AcceptedOrValidation, AcmeAccept, AcmeAcceptor};
 use rustls::ServerConfig;
 use std::future::Future;
 use std::io;
-use std::io::ErrorKind;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
@@ -69,16 +68,15 @@ impl<I: AsyncRead + AsyncWrite + Unpin + Send + 'static, S: Send + 'static> Futu
                 };
             }
             return match Pin::new(&mut self.acme_accept).poll(cx) {
-                Poll::Ready(Ok(Some(start_handshake))) => {
+                Poll::Ready(Ok(AcceptedOrValidation::Accepted(start_handshake))) => {
                     let config = self.config.clone();
                     self.tls_accept = Some(start_handshake.into_stream(config));
                     continue;
                 }
-                Poll::Ready(Ok(None)) => Poll::Ready(Err(io::Error::new(
-                    ErrorKind::Other,
-                    "TLS-ALPN-01 validation request",
-                ))),
-                Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+                Poll::Ready(Ok(AcceptedOrValidation::Validation)) => {
+                    Poll::Ready(Err(io::Error::other("TLS-ALPN-01 validation request")))
+                }
+                Poll::Ready(Err(err)) => Poll::Ready(Err(err.into())),
                 Poll::Pending => Poll::Pending,
             };
         }
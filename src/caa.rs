@@ -0,0 +1,112 @@
+//! Pre-flight check of the CAA records of the ordered domains
+//! ([RFC 8659](https://www.rfc-editor.org/rfc/rfc8659)).
+
+use crate::logging;
+use crate::OrderError;
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::proto::rr::rdata::caa::{Property, Value};
+use hickory_resolver::proto::rr::{RData, RecordType};
+use hickory_resolver::TokioAsyncResolver;
+
+/// Fail with [OrderError::CaaForbidden] if the CAA records of a domain allow none of the
+/// `caa_identities` of the CA to issue for it.
+///
+/// Lookup failures are logged and skip the check, leaving the decision to the CA.
+pub(crate) async fn check(domains: &[String], caa_identities: &[String]) -> Result<(), OrderError> {
+    let resolver = match TokioAsyncResolver::tokio_from_system_conf() {
+        Ok(resolver) => resolver,
+        Err(err) => {
+            logging::warn!("skipping CAA check, could not create resolver: {}", err);
+            return Ok(());
+        }
+    };
+    for domain in domains {
+        let (name, wildcard) = match domain.strip_prefix("*.") {
+            Some(name) => (name, true),
+            None => (domain.as_str(), false),
+        };
+        let records = match lookup(&resolver, name).await {
+            Ok(records) => records,
+            Err(err) => {
+                logging::warn!("skipping CAA check for {}: {}", domain, err);
+                continue;
+            }
+        };
+        let issuewild: Vec<_> = records
+            .iter()
+            .filter(|(property, _)| *property == Property::IssueWild)
+            .collect();
+        let relevant: Vec<_> = match wildcard && !issuewild.is_empty() {
+            true => issuewild,
+            false => records
+                .iter()
+                .filter(|(property, _)| *property == Property::Issue)
+                .collect(),
+        };
+        if relevant.is_empty() {
+            continue;
+        }
+        let allowed = relevant.iter().any(|(_, issuer)| {
+            issuer.as_ref().is_some_and(|issuer| {
+                caa_identities
+                    .iter()
+                    .any(|id| id.eq_ignore_ascii_case(issuer))
+            })
+        });
+        if !allowed {
+            return Err(OrderError::CaaForbidden {
+                domain: domain.clone(),
+                records: relevant
+                    .iter()
+                    .map(|(property, issuer)| {
+                        format!("{} \"{}\"", property, issuer.as_deref().unwrap_or(";"))
+                    })
+                    .collect(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// The `issue` and `issuewild` records with their issuer domain applying to `name`, found at the
+/// closest ancestor with CAA records.
+async fn lookup(
+    resolver: &TokioAsyncResolver,
+    name: &str,
+) -> Result<Vec<(Property, Option<String>)>, hickory_resolver::error::ResolveError> {
+    let mut name = name.trim_end_matches('.');
+    loop {
+        let lookup = match resolver.lookup(format!("{}.", name), RecordType::CAA).await {
+            Ok(lookup) => Some(lookup),
+            Err(err) if matches!(err.kind(), ResolveErrorKind::NoRecordsFound { .. }) => None,
+            Err(err) => return Err(err),
+        };
+        let records: Vec<_> = lookup
+            .iter()
+            .flat_map(|lookup| lookup.iter())
+            .filter_map(|rdata| match rdata {
+                RData::CAA(caa) => Some(caa),
+                _ => None,
+            })
+            .collect();
+        if !records.is_empty() {
+            return Ok(records
+                .into_iter()
+                .filter(|caa| matches!(caa.tag(), Property::Issue | Property::IssueWild))
+                .map(|caa| {
+                    let issuer = match caa.value() {
+                        Value::Issuer(issuer, _) => issuer
+                            .as_ref()
+                            .map(|issuer| issuer.to_ascii().trim_end_matches('.').to_string()),
+                        _ => None,
+                    };
+                    (caa.tag().clone(), issuer)
+                })
+                .collect());
+        }
+        match name.split_once('.') {
+            Some((_, parent)) if !parent.is_empty() => name = parent,
+            _ => return Ok(Vec::new()),
+        }
+    }
+}
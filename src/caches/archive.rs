@@ -0,0 +1,180 @@
+//! Export and import the contents of a [DirCache] as a single portable archive.
+//!
+//! The archive is a JSON document listing every cached account, certificate and order file by
+//! name together with its base64 encoded contents. Orders are included so that an order in flight
+//! can be resumed after migrating, see
+//! [AcmeConfig::resume_orders](crate::AcmeConfig::resume_orders). It is meant for backups and for migrating a cache
+//! to another machine.
+//!
+//! **The archive contains the account private keys and the private keys of all cached
+//! certificates.** Treat it with the same care as the cache directory itself: restrict its file
+//! permissions, encrypt it at rest and never commit it to version control.
+
+use crate::caches::DirCache;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::Path;
+use tokio::fs;
+
+const ARCHIVE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Archive {
+    version: u32,
+    entries: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    name: String,
+    contents: String,
+}
+
+fn is_cache_file_name(name: &str) -> bool {
+    ["cached_account_", "cached_cert_", "cached_order_"]
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// Write all cached accounts, certificates and orders of `cache` to `out`.
+///
+/// Returns the number of exported entries. A cache directory that does not exist yet is exported
+/// as an empty archive.
+pub async fn export<P: AsRef<Path> + Send + Sync>(
+    cache: &DirCache<P>,
+    out: impl Write,
+) -> io::Result<usize> {
+    let mut entries = read_entries(cache.directory()).await?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    let count = entries.len();
+    let archive = Archive {
+        version: ARCHIVE_VERSION,
+        entries,
+    };
+    serde_json::to_writer_pretty(out, &archive)?;
+    Ok(count)
+}
+
+async fn read_entries(dir: &Path) -> io::Result<Vec<Entry>> {
+    let mut dir = match fs::read_dir(dir).await {
+        Ok(dir) => dir,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut entries = Vec::new();
+    while let Some(entry) = dir.next_entry().await? {
+        let name = match entry.file_name().into_string() {
+            Ok(name) if is_cache_file_name(&name) => name,
+            _ => continue,
+        };
+        if !entry.file_type().await?.is_file() {
+            continue;
+        }
+        let contents = fs::read(entry.path()).await?;
+        entries.push(Entry {
+            name,
+            contents: STANDARD.encode(contents),
+        });
+    }
+    Ok(entries)
+}
+
+/// Restore the entries of an archive created by [export] into `cache`.
+///
+/// Existing entries with the same name are overwritten. Returns the number of imported entries.
+pub async fn import<P: AsRef<Path> + Send + Sync>(
+    cache: &DirCache<P>,
+    input: impl Read,
+) -> io::Result<usize> {
+    let archive: Archive = serde_json::from_reader(input)?;
+    if archive.version != ARCHIVE_VERSION {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported cache archive version {}", archive.version),
+        ));
+    }
+    let mut files = Vec::with_capacity(archive.entries.len());
+    for entry in archive.entries {
+        if !is_cache_file_name(&entry.name) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid cache archive entry name {:?}", entry.name),
+            ));
+        }
+        let contents = STANDARD
+            .decode(entry.contents)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+        files.push((entry.name, contents));
+    }
+    for (name, contents) in &files {
+        cache.write(name, contents).await?;
+    }
+    Ok(files.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AccountCache, CertCache};
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let name = format!("tokio-rustls-acme-{}-{}", name, std::process::id());
+        std::env::temp_dir().join(name)
+    }
+
+    #[tokio::test]
+    async fn export_then_import_restores_the_cache() {
+        let (from, to) = (temp_dir("archive-from"), temp_dir("archive-to"));
+        let domains = vec!["example.com".to_string()];
+        let contact = vec!["mailto:admin@example.com".to_string()];
+        let directory_url = "https://acme.example/directory";
+        let source = DirCache::new(from.clone());
+        source
+            .store_cert(&domains, directory_url, b"cert")
+            .await
+            .unwrap();
+        source
+            .store_account(&contact, directory_url, b"account")
+            .await
+            .unwrap();
+        source
+            .store_order(&domains, directory_url, Some("https://acme.example/order"))
+            .await
+            .unwrap();
+        std::fs::write(from.join("unrelated.txt"), b"not a cache file").unwrap();
+
+        let mut archive = Vec::new();
+        assert_eq!(export(&source, &mut archive).await.unwrap(), 3);
+        let target = DirCache::new(to.clone());
+        assert_eq!(import(&target, archive.as_slice()).await.unwrap(), 3);
+        let cert = target.load_cert(&domains, directory_url).await.unwrap();
+        assert_eq!(cert.as_deref(), Some(&b"cert"[..]));
+        let account = target.load_account(&contact, directory_url).await.unwrap();
+        assert_eq!(account.as_deref(), Some(&b"account"[..]));
+        let order = target.load_order(&domains, directory_url).await.unwrap();
+        assert_eq!(order.as_deref(), Some("https://acme.example/order"));
+        assert!(!to.join("unrelated.txt").exists());
+
+        std::fs::remove_dir_all(from).unwrap();
+        std::fs::remove_dir_all(to).unwrap();
+    }
+
+    #[tokio::test]
+    async fn import_rejects_names_that_are_not_cache_files() {
+        let dir = temp_dir("archive-reject");
+        let cache = DirCache::new(dir.clone());
+        for name in ["unrelated.txt", "../cached_cert_x", "cached_cert_x/y"] {
+            let entries = serde_json::json!([{ "name": name, "contents": "" }]);
+            let archive = serde_json::json!({ "version": ARCHIVE_VERSION, "entries": entries });
+            let err = import(&cache, archive.to_string().as_bytes()).await;
+            assert_eq!(err.unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+        assert!(!dir.exists());
+    }
+}
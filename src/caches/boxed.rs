@@ -25,6 +25,9 @@ where
     <T as CertCache>::EC: 'static,
 {
     type EC = Box<dyn Debug>;
+    fn is_durable(&self) -> bool {
+        self.inner.is_durable()
+    }
     async fn load_cert(
         &self,
         domains: &[String],
@@ -47,6 +50,36 @@ where
             .await
             .map_err(box_err)
     }
+
+    async fn remove_cert(&self, domains: &[String], directory_url: &str) -> Result<(), Self::EC> {
+        self.inner
+            .remove_cert(domains, directory_url)
+            .await
+            .map_err(box_err)
+    }
+
+    async fn load_order(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+    ) -> Result<Option<String>, Self::EC> {
+        self.inner
+            .load_order(domains, directory_url)
+            .await
+            .map_err(box_err)
+    }
+
+    async fn store_order(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+        order_url: Option<&str>,
+    ) -> Result<(), Self::EC> {
+        self.inner
+            .store_order(domains, directory_url, order_url)
+            .await
+            .map_err(box_err)
+    }
 }
 
 #[async_trait]
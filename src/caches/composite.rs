@@ -21,6 +21,9 @@ impl<C: CertCache + Send + Sync, A: AccountCache + Send + Sync> CompositeCache<C
 #[async_trait]
 impl<C: CertCache + Send + Sync, A: AccountCache + Send + Sync> CertCache for CompositeCache<C, A> {
     type EC = C::EC;
+    fn is_durable(&self) -> bool {
+        self.cert_cache.is_durable()
+    }
     async fn load_cert(
         &self,
         domains: &[String],
@@ -39,6 +42,29 @@ impl<C: CertCache + Send + Sync, A: AccountCache + Send + Sync> CertCache for Co
             .store_cert(domains, directory_url, cert)
             .await
     }
+
+    async fn remove_cert(&self, domains: &[String], directory_url: &str) -> Result<(), Self::EC> {
+        self.cert_cache.remove_cert(domains, directory_url).await
+    }
+
+    async fn load_order(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+    ) -> Result<Option<String>, Self::EC> {
+        self.cert_cache.load_order(domains, directory_url).await
+    }
+
+    async fn store_order(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+        order_url: Option<&str>,
+    ) -> Result<(), Self::EC> {
+        self.cert_cache
+            .store_order(domains, directory_url, order_url)
+            .await
+    }
 }
 
 #[async_trait]
@@ -1,19 +1,41 @@
-use crate::{AccountCache, CertCache};
+use crate::cache::decode_cache_entry;
+use crate::certificate;
+use crate::crypto::backend::digest::{Context, SHA256};
+use crate::logging;
+use crate::{AccountCache, CertCache, Clock, SystemClock};
 use async_trait::async_trait;
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine;
-use ring::digest::{Context, SHA256};
+use chrono::{DateTime, Utc};
 use std::io::ErrorKind;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs;
 
+/// Version of the cache file names, bumped whenever the format of the entries changes.
+const CACHE_KEY_VERSION: &str = "v1";
+
 pub struct DirCache<P: AsRef<Path> + Send + Sync> {
     inner: P,
+    clock: Arc<dyn Clock>,
 }
 
 impl<P: AsRef<Path> + Send + Sync> DirCache<P> {
     pub fn new(dir: P) -> Self {
-        Self { inner: dir }
+        Self {
+            inner: dir,
+            clock: Arc::new(SystemClock),
+        }
+    }
+    /// Take the current time for [DirCache::prune_expired] from `clock` instead of the system
+    /// clock, e.g. the one set with [AcmeConfig::clock](crate::AcmeConfig::clock).
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+    pub(crate) fn directory(&self) -> &Path {
+        self.inner.as_ref()
     }
     async fn read_if_exist(
         &self,
@@ -28,7 +50,14 @@ impl<P: AsRef<Path> + Send + Sync> DirCache<P> {
             },
         }
     }
-    async fn write(
+    async fn remove_if_exist(&self, file: impl AsRef<Path>) -> Result<(), std::io::Error> {
+        let path = self.inner.as_ref().join(file);
+        match fs::remove_file(path).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+    pub(crate) async fn write(
         &self,
         file: impl AsRef<Path>,
         contents: impl AsRef<[u8]>,
@@ -38,26 +67,99 @@ impl<P: AsRef<Path> + Send + Sync> DirCache<P> {
         fs::write(path, contents).await
     }
 
-    fn cached_account_file_name(contact: &[String], directory_url: impl AsRef<str>) -> String {
-        let mut ctx = Context::new(&SHA256);
-        for el in contact {
-            ctx.update(el.as_ref());
-            ctx.update(&[0])
+    /// Read `file`, falling back to `legacy_file` written by releases before the versioned names.
+    async fn read_versioned(
+        &self,
+        file: impl AsRef<Path>,
+        legacy_file: impl AsRef<Path>,
+    ) -> Result<Option<Vec<u8>>, std::io::Error> {
+        match self.read_if_exist(file).await? {
+            Some(content) => Ok(Some(content)),
+            None => self.read_if_exist(legacy_file).await,
         }
-        ctx.update(directory_url.as_ref().as_bytes());
-        let hash = URL_SAFE_NO_PAD.encode(ctx.finish());
-        format!("cached_account_{}", hash)
     }
-    fn cached_cert_file_name(domains: &[String], directory_url: impl AsRef<str>) -> String {
-        let mut ctx = Context::new(&SHA256);
-        for domain in domains {
-            ctx.update(domain.as_ref());
-            ctx.update(&[0])
+
+    /// Remove cached certificates that expired more than `grace` ago, e.g. for domains no longer
+    /// served. Returns the paths of the removed files.
+    ///
+    /// Files that cannot be parsed as certificates are left alone, as are account keys, which
+    /// do not expire, and the empty entries of discarded certificates.
+    pub async fn prune_expired(&self, grace: Duration) -> Result<Vec<PathBuf>, std::io::Error> {
+        let grace = chrono::Duration::from_std(grace).unwrap_or(chrono::Duration::MAX);
+        let now = self.clock.now();
+        let mut removed = Vec::new();
+        let mut entries = match fs::read_dir(&self.inner).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(removed),
+            Err(err) => return Err(err),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let is_cert = entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with("cached_cert_");
+            if !is_cert || !entry.file_type().await?.is_file() {
+                continue;
+            }
+            let path = entry.path();
+            let contents = fs::read(&path).await?;
+            if contents.is_empty() {
+                continue;
+            }
+            let not_after = match cert_not_after(&contents) {
+                Some(not_after) => not_after,
+                None => {
+                    logging::warn!("not pruning unparseable cache file {:?}", path);
+                    continue;
+                }
+            };
+            if not_after
+                .checked_add_signed(grace)
+                .is_some_and(|end| end < now)
+            {
+                fs::remove_file(&path).await?;
+                removed.push(path);
+            }
         }
-        ctx.update(directory_url.as_ref().as_bytes());
-        let hash = URL_SAFE_NO_PAD.encode(ctx.finish());
-        format!("cached_cert_{}", hash)
+        Ok(removed)
+    }
+
+    fn cached_account_file_name(contact: &[String], directory_url: impl AsRef<str>) -> String {
+        let hash = cache_hash(contact, directory_url);
+        format!("cached_account_{}_{}", CACHE_KEY_VERSION, hash)
+    }
+    fn cached_cert_file_name(domains: &[String], directory_url: impl AsRef<str>) -> String {
+        let hash = cache_hash(domains, directory_url);
+        format!("cached_cert_{}_{}", CACHE_KEY_VERSION, hash)
+    }
+    fn cached_order_file_name(domains: &[String], directory_url: impl AsRef<str>) -> String {
+        let hash = cache_hash(domains, directory_url);
+        format!("cached_order_{}_{}", CACHE_KEY_VERSION, hash)
+    }
+    fn legacy_account_file_name(contact: &[String], directory_url: impl AsRef<str>) -> String {
+        format!("cached_account_{}", cache_hash(contact, directory_url))
+    }
+    fn legacy_cert_file_name(domains: &[String], directory_url: impl AsRef<str>) -> String {
+        format!("cached_cert_{}", cache_hash(domains, directory_url))
+    }
+}
+
+/// Not-after of the leaf certificate in a cached certificate entry.
+fn cert_not_after(entry: &[u8]) -> Option<DateTime<Utc>> {
+    let pems = pem::parse_many(decode_cache_entry(entry).ok()?).ok()?;
+    let leaf = pems.iter().find(|pem| pem.tag() == "CERTIFICATE")?;
+    Some(certificate::validity(leaf.contents()).ok()?[1])
+}
+
+/// Hash identifying the cache entry for a list of domains or contacts and a directory.
+pub(crate) fn cache_hash(items: &[String], directory_url: impl AsRef<str>) -> String {
+    let mut ctx = Context::new(&SHA256);
+    for el in items {
+        ctx.update(el.as_ref());
+        ctx.update(&[0])
     }
+    ctx.update(directory_url.as_ref().as_bytes());
+    URL_SAFE_NO_PAD.encode(ctx.finish())
 }
 
 #[async_trait]
@@ -69,7 +171,8 @@ impl<P: AsRef<Path> + Send + Sync> CertCache for DirCache<P> {
         directory_url: &str,
     ) -> Result<Option<Vec<u8>>, Self::EC> {
         let file_name = Self::cached_cert_file_name(domains, directory_url);
-        self.read_if_exist(file_name).await
+        let legacy_file_name = Self::legacy_cert_file_name(domains, directory_url);
+        self.read_versioned(file_name, legacy_file_name).await
     }
     async fn store_cert(
         &self,
@@ -80,6 +183,38 @@ impl<P: AsRef<Path> + Send + Sync> CertCache for DirCache<P> {
         let file_name = Self::cached_cert_file_name(domains, directory_url);
         self.write(file_name, cert).await
     }
+    async fn remove_cert(&self, domains: &[String], directory_url: &str) -> Result<(), Self::EC> {
+        let file_name = Self::cached_cert_file_name(domains, directory_url);
+        let legacy_file_name = Self::legacy_cert_file_name(domains, directory_url);
+        self.remove_if_exist(file_name).await?;
+        self.remove_if_exist(legacy_file_name).await
+    }
+    async fn load_order(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+    ) -> Result<Option<String>, Self::EC> {
+        let file_name = Self::cached_order_file_name(domains, directory_url);
+        match self.read_if_exist(file_name).await? {
+            Some(content) => match String::from_utf8(content) {
+                Ok(order_url) => Ok(Some(order_url)),
+                Err(err) => Err(std::io::Error::new(ErrorKind::InvalidData, err)),
+            },
+            None => Ok(None),
+        }
+    }
+    async fn store_order(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+        order_url: Option<&str>,
+    ) -> Result<(), Self::EC> {
+        let file_name = Self::cached_order_file_name(domains, directory_url);
+        match order_url {
+            Some(order_url) => self.write(file_name, order_url).await,
+            None => self.remove_if_exist(file_name).await,
+        }
+    }
 }
 
 #[async_trait]
@@ -91,7 +226,8 @@ impl<P: AsRef<Path> + Send + Sync> AccountCache for DirCache<P> {
         directory_url: &str,
     ) -> Result<Option<Vec<u8>>, Self::EA> {
         let file_name = Self::cached_account_file_name(contact, directory_url);
-        self.read_if_exist(file_name).await
+        let legacy_file_name = Self::legacy_account_file_name(contact, directory_url);
+        self.read_versioned(file_name, legacy_file_name).await
     }
 
     async fn store_account(
@@ -104,3 +240,125 @@ impl<P: AsRef<Path> + Send + Sync> AccountCache for DirCache<P> {
         self.write(file_name, account).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let name = format!("tokio-rustls-acme-{}-{}", name, std::process::id());
+        std::env::temp_dir().join(name)
+    }
+
+    #[tokio::test]
+    async fn legacy_file_names_are_still_read() {
+        let dir = temp_dir("dir-legacy");
+        let cache = DirCache::new(dir.clone());
+        let domains = vec!["example.com".to_string()];
+        let directory_url = "https://acme.example/directory";
+        let legacy_file_name = DirCache::<PathBuf>::legacy_cert_file_name(&domains, directory_url);
+        cache.write(&legacy_file_name, b"legacy").await.unwrap();
+        let cert = cache.load_cert(&domains, directory_url).await.unwrap();
+        assert_eq!(cert.as_deref(), Some(&b"legacy"[..]));
+
+        cache
+            .store_cert(&domains, directory_url, b"cert")
+            .await
+            .unwrap();
+        let cert = cache.load_cert(&domains, directory_url).await.unwrap();
+        assert_eq!(cert.as_deref(), Some(&b"cert"[..]));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    struct FixedClock(DateTime<Utc>);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.0
+        }
+    }
+
+    /// Collects the messages logged through `log`, so only without the `tracing` feature.
+    struct CapturedLogs(std::sync::Mutex<Vec<String>>);
+
+    impl log::Log for CapturedLogs {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+        fn flush(&self) {}
+    }
+
+    static LOGS: CapturedLogs = CapturedLogs(std::sync::Mutex::new(Vec::new()));
+
+    fn cert_valid_until(year: i32) -> Vec<u8> {
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(vec!["example.com".to_string()]).unwrap();
+        params.not_after = rcgen::date_time_ymd(year, 1, 1);
+        params.self_signed(&key_pair).unwrap().pem().into_bytes()
+    }
+
+    #[tokio::test]
+    async fn prune_expired_only_removes_expired_certs() {
+        let _ = log::set_logger(&LOGS);
+        log::set_max_level(log::LevelFilter::Warn);
+        let dir = temp_dir("dir-prune");
+        let now = chrono::TimeZone::with_ymd_and_hms(&Utc, 2025, 1, 1, 0, 0, 0).unwrap();
+        let cache = DirCache::new(dir.clone()).clock(FixedClock(now));
+        let directory_url = "https://acme.example/directory";
+        let expired = vec!["expired.example.com".to_string()];
+        let valid = vec!["valid.example.com".to_string()];
+        let discarded = vec!["discarded.example.com".to_string()];
+        let contact = vec!["mailto:admin@example.com".to_string()];
+        let entries: [(&[String], Vec<u8>); 3] = [
+            (&expired, cert_valid_until(2024)),
+            (&valid, cert_valid_until(2026)),
+            (&discarded, Vec::new()),
+        ];
+        for (domains, cert) in entries {
+            cache
+                .store_cert(domains, directory_url, &cert)
+                .await
+                .unwrap();
+        }
+        cache
+            .store_account(&contact, directory_url, b"account")
+            .await
+            .unwrap();
+
+        let removed = cache.prune_expired(Duration::from_secs(60)).await.unwrap();
+        let expired_file_name = DirCache::<PathBuf>::cached_cert_file_name(&expired, directory_url);
+        assert_eq!(removed, vec![dir.join(expired_file_name)]);
+        assert!(cache
+            .load_cert(&valid, directory_url)
+            .await
+            .unwrap()
+            .is_some());
+        let discarded_file_name =
+            DirCache::<PathBuf>::cached_cert_file_name(&discarded, directory_url);
+        assert!(dir.join(&discarded_file_name).exists());
+        assert!(cache
+            .load_account(&contact, directory_url)
+            .await
+            .unwrap()
+            .is_some());
+        let logs = LOGS.0.lock().unwrap();
+        assert!(!logs
+            .iter()
+            .any(|message| message.contains(&discarded_file_name)));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing_files_are_a_miss() {
+        let cache = DirCache::new(temp_dir("dir-missing"));
+        let contact = vec!["mailto:admin@example.com".to_string()];
+        let account = cache.load_account(&contact, "https://acme.example/directory");
+        assert_eq!(account.await.unwrap(), None);
+    }
+}
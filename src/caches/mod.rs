@@ -1,11 +1,18 @@
+pub mod archive;
 mod boxed;
 mod composite;
 mod dir;
 mod no;
+#[cfg(feature = "sqlite")]
+mod sqlite;
 mod test;
+mod tiered;
 
 pub use boxed::*;
 pub use composite::*;
 pub use dir::*;
 pub use no::*;
+#[cfg(feature = "sqlite")]
+pub use sqlite::*;
 pub use test::*;
+pub use tiered::*;
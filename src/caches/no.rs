@@ -1,3 +1,4 @@
+use crate::logging;
 use crate::{AccountCache, CertCache};
 use async_trait::async_trait;
 use std::convert::Infallible;
@@ -36,12 +37,15 @@ impl<EC: Debug, EA: Debug> NoCache<EC, EA> {
 #[async_trait]
 impl<EC: Debug, EA: Debug> CertCache for NoCache<EC, EA> {
     type EC = EC;
+    fn is_durable(&self) -> bool {
+        false
+    }
     async fn load_cert(
         &self,
         _domains: &[String],
         _directory_url: &str,
     ) -> Result<Option<Vec<u8>>, Self::EC> {
-        log::info!("no cert cache configured, could not load certificate");
+        logging::info!("no cert cache configured, could not load certificate");
         Ok(None)
     }
     async fn store_cert(
@@ -50,7 +54,7 @@ impl<EC: Debug, EA: Debug> CertCache for NoCache<EC, EA> {
         _directory_url: &str,
         _cert: &[u8],
     ) -> Result<(), Self::EC> {
-        log::info!("no cert cache configured, could not store certificate");
+        logging::info!("no cert cache configured, could not store certificate");
         Ok(())
     }
 }
@@ -63,7 +67,7 @@ impl<EC: Debug, EA: Debug> AccountCache for NoCache<EC, EA> {
         _contact: &[String],
         _directory_url: &str,
     ) -> Result<Option<Vec<u8>>, Self::EA> {
-        log::info!("no account cache configured, could not load account");
+        logging::info!("no account cache configured, could not load account");
         Ok(None)
     }
     async fn store_account(
@@ -72,7 +76,7 @@ impl<EC: Debug, EA: Debug> AccountCache for NoCache<EC, EA> {
         _directory_url: &str,
         _account: &[u8],
     ) -> Result<(), Self::EA> {
-        log::info!("no account cache configured, could not store account");
+        logging::info!("no account cache configured, could not store account");
         Ok(())
     }
 }
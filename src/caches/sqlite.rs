@@ -0,0 +1,202 @@
+use crate::caches::dir::cache_hash;
+use crate::{AccountCache, CertCache};
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Cache storing certificates and account keys in a single SQLite database.
+///
+/// Entries are keyed on their kind (`cert`, `account` or `order`) and the same hash
+/// [DirCache](crate::caches::DirCache) uses for its file names. The table is created when the cache
+/// is opened, if it does not exist.
+#[derive(Clone)]
+pub struct SqliteCache {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteCache {
+    /// Open or create the database at `path`.
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        Self::new(Connection::open(path)?)
+    }
+    /// Use an existing connection.
+    pub fn new(conn: Connection) -> rusqlite::Result<Self> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS tokio_rustls_acme_cache (
+                kind TEXT NOT NULL,
+                hash TEXT NOT NULL,
+                contents BLOB NOT NULL,
+                updated_at INTEGER NOT NULL,
+                PRIMARY KEY (kind, hash)
+            )",
+            [],
+        )?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+    async fn with_conn<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> rusqlite::Result<T> {
+        let conn = self.conn.clone();
+        let task = tokio::task::spawn_blocking(move || f(&conn.lock().unwrap()));
+        match task.await {
+            Ok(result) => result,
+            Err(err) => std::panic::resume_unwind(err.into_panic()),
+        }
+    }
+    async fn load(&self, kind: &'static str, hash: String) -> rusqlite::Result<Option<Vec<u8>>> {
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT contents FROM tokio_rustls_acme_cache WHERE kind = ?1 AND hash = ?2",
+                params![kind, hash],
+                |row| row.get(0),
+            )
+            .optional()
+        })
+        .await
+    }
+    async fn store(
+        &self,
+        kind: &'static str,
+        hash: String,
+        contents: Vec<u8>,
+    ) -> rusqlite::Result<()> {
+        let updated_at = chrono::Utc::now().timestamp();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO tokio_rustls_acme_cache (kind, hash, contents, updated_at)
+                VALUES (?1, ?2, ?3, ?4)",
+                params![kind, hash, contents, updated_at],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+    async fn remove(&self, kind: &'static str, hash: String) -> rusqlite::Result<()> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "DELETE FROM tokio_rustls_acme_cache WHERE kind = ?1 AND hash = ?2",
+                params![kind, hash],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl CertCache for SqliteCache {
+    type EC = rusqlite::Error;
+    async fn load_cert(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+    ) -> Result<Option<Vec<u8>>, Self::EC> {
+        self.load("cert", cache_hash(domains, directory_url)).await
+    }
+    async fn store_cert(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+        cert: &[u8],
+    ) -> Result<(), Self::EC> {
+        let hash = cache_hash(domains, directory_url);
+        self.store("cert", hash, cert.to_vec()).await
+    }
+    async fn remove_cert(&self, domains: &[String], directory_url: &str) -> Result<(), Self::EC> {
+        self.remove("cert", cache_hash(domains, directory_url))
+            .await
+    }
+    async fn load_order(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+    ) -> Result<Option<String>, Self::EC> {
+        let contents = self
+            .load("order", cache_hash(domains, directory_url))
+            .await?;
+        Ok(contents.map(|contents| String::from_utf8_lossy(&contents).into_owned()))
+    }
+    async fn store_order(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+        order_url: Option<&str>,
+    ) -> Result<(), Self::EC> {
+        let hash = cache_hash(domains, directory_url);
+        match order_url {
+            Some(order_url) => self.store("order", hash, order_url.into()).await,
+            None => self.remove("order", hash).await,
+        }
+    }
+}
+
+#[async_trait]
+impl AccountCache for SqliteCache {
+    type EA = rusqlite::Error;
+    async fn load_account(
+        &self,
+        contact: &[String],
+        directory_url: &str,
+    ) -> Result<Option<Vec<u8>>, Self::EA> {
+        self.load("account", cache_hash(contact, directory_url))
+            .await
+    }
+    async fn store_account(
+        &self,
+        contact: &[String],
+        directory_url: &str,
+        account: &[u8],
+    ) -> Result<(), Self::EA> {
+        let hash = cache_hash(contact, directory_url);
+        self.store("account", hash, account.to_vec()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stores_loads_and_overwrites_entries() {
+        let cache = SqliteCache::open(":memory:").unwrap();
+        let names = vec!["example.com".to_string()];
+        let directory_url = "https://acme.example/directory";
+        assert_eq!(cache.load_cert(&names, directory_url).await.unwrap(), None);
+        assert_eq!(
+            cache.load_account(&names, directory_url).await.unwrap(),
+            None
+        );
+
+        cache
+            .store_cert(&names, directory_url, b"cert")
+            .await
+            .unwrap();
+        cache
+            .store_account(&names, directory_url, b"account")
+            .await
+            .unwrap();
+        let cert = cache.load_cert(&names, directory_url).await.unwrap();
+        assert_eq!(cert.as_deref(), Some(&b"cert"[..]));
+        let account = cache.load_account(&names, directory_url).await.unwrap();
+        assert_eq!(account.as_deref(), Some(&b"account"[..]));
+
+        cache
+            .store_cert(&names, directory_url, b"renewed")
+            .await
+            .unwrap();
+        cache
+            .store_account(&names, directory_url, b"rotated")
+            .await
+            .unwrap();
+        let cert = cache.load_cert(&names, directory_url).await.unwrap();
+        assert_eq!(cert.as_deref(), Some(&b"renewed"[..]));
+        let account = cache.load_account(&names, directory_url).await.unwrap();
+        assert_eq!(account.as_deref(), Some(&b"rotated"[..]));
+        let other = cache.load_cert(&names, "https://other.example/directory");
+        assert_eq!(other.await.unwrap(), None);
+    }
+}
@@ -1,15 +1,21 @@
+use crate::logging;
 use crate::{AccountCache, CertCache};
 use async_trait::async_trait;
 use rcgen::{
     date_time_ymd, BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa,
-    KeyUsagePurpose, PKCS_ECDSA_P256_SHA256,
+    KeyUsagePurpose, SignatureAlgorithm, PKCS_ECDSA_P256_SHA256,
 };
 use std::fmt::Debug;
 use std::marker::PhantomData;
 use std::sync::atomic::AtomicPtr;
 use std::sync::Arc;
+use std::time::Duration;
+use time::OffsetDateTime;
 
 /// Test cache, which generates certificates for ACME incompatible test environments.
+///
+/// Each loaded certificate is valid from now for 90 days, unless configured otherwise with
+/// [TestCache::with_validity], so it is renewed like a certificate issued by Let's Encrypt.
 /// ```rust
 /// # use tokio_rustls_acme::{AcmeConfig};
 /// # use tokio_rustls_acme::caches::{DirCache, TestCache};
@@ -25,6 +31,8 @@ pub struct TestCache<EC: Debug = std::io::Error, EA: Debug = std::io::Error> {
     ca_cert: Arc<rcgen::Certificate>,
     ca_pem: Arc<String>,
     ca_key_pair: Arc<rcgen::KeyPair>,
+    key_alg: &'static SignatureAlgorithm,
+    validity: Duration,
     _cert_error: PhantomData<AtomicPtr<Box<EC>>>,
     _account_error: PhantomData<AtomicPtr<Box<EA>>>,
 }
@@ -50,6 +58,8 @@ impl<EC: Debug, EA: Debug> Default for TestCache<EC, EA> {
             ca_cert: ca_cert.into(),
             ca_key_pair: key_pair.into(),
             ca_pem: ca_pem.into(),
+            key_alg: &PKCS_ECDSA_P256_SHA256,
+            validity: Duration::from_secs(90 * 24 * 60 * 60),
             _cert_error: Default::default(),
             _account_error: Default::default(),
         }
@@ -64,6 +74,18 @@ impl<EC: Debug, EA: Debug> TestCache<EC, EA> {
     pub fn ca_pem(&self) -> &str {
         &self.ca_pem
     }
+
+    /// Generate the certificate keys with `key_alg`, which must be an ECDSA algorithm.
+    pub fn with_key_alg(mut self, key_alg: &'static SignatureAlgorithm) -> Self {
+        self.key_alg = key_alg;
+        self
+    }
+
+    /// Make certificates valid from the time they are loaded for `validity`.
+    pub fn with_validity(mut self, validity: Duration) -> Self {
+        self.validity = validity;
+        self
+    }
 }
 
 #[async_trait]
@@ -74,18 +96,24 @@ impl<EC: Debug, EA: Debug> CertCache for TestCache<EC, EA> {
         domains: &[String],
         _directory_url: &str,
     ) -> Result<Option<Vec<u8>>, Self::EC> {
-        let key_pair = rcgen::KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let key_pair = match rcgen::KeyPair::generate_for(self.key_alg) {
+            Ok(key_pair) => key_pair,
+            Err(err) => {
+                logging::error!("test cache: key generation error: {:?}", err);
+                return Ok(None);
+            }
+        };
         let mut params = CertificateParams::new(domains).unwrap();
         let mut distinguished_name = DistinguishedName::new();
         distinguished_name.push(DnType::CommonName, "Test Cert");
         params.distinguished_name = distinguished_name;
-        params.not_before = date_time_ymd(2000, 1, 1);
-        params.not_after = date_time_ymd(3000, 1, 1);
+        params.not_before = OffsetDateTime::now_utc();
+        params.not_after = params.not_before + self.validity;
 
         let cert = match params.signed_by(&key_pair, &self.ca_cert, &self.ca_key_pair) {
             Ok(cert) => cert,
             Err(err) => {
-                log::error!("test cache: generation error: {:?}", err);
+                logging::error!("test cache: generation error: {:?}", err);
                 return Ok(None);
             }
         };
@@ -108,7 +136,7 @@ impl<EC: Debug, EA: Debug> CertCache for TestCache<EC, EA> {
         _directory_url: &str,
         _cert: &[u8],
     ) -> Result<(), Self::EC> {
-        log::info!("test cache configured, could not store certificate");
+        logging::info!("test cache configured, could not store certificate");
         Ok(())
     }
 }
@@ -121,7 +149,7 @@ impl<EC: Debug, EA: Debug> AccountCache for TestCache<EC, EA> {
         _contact: &[String],
         _directory_url: &str,
     ) -> Result<Option<Vec<u8>>, Self::EA> {
-        log::info!("test cache configured, could not load account");
+        logging::info!("test cache configured, could not load account");
         Ok(None)
     }
     async fn store_account(
@@ -130,7 +158,7 @@ impl<EC: Debug, EA: Debug> AccountCache for TestCache<EC, EA> {
         _directory_url: &str,
         _account: &[u8],
     ) -> Result<(), Self::EA> {
-        log::info!("test cache configured, could not store account");
+        logging::info!("test cache configured, could not store account");
         Ok(())
     }
 }
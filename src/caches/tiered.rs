@@ -0,0 +1,298 @@
+use crate::logging;
+use crate::{AccountCache, CertCache};
+use async_trait::async_trait;
+use std::fmt::Debug;
+
+/// Cache reading from a `primary` cache first and from a `secondary` cache on a miss, while
+/// writing to both.
+///
+/// Meant for a fast local cache, like a [DirCache](crate::caches::DirCache), in front of a cache
+/// shared by several servers. Tiers can be nested to use more than two caches.
+///
+/// * A load returns the first hit. A hit in `secondary` is copied to `primary`, failing to do so
+///   is only logged. An error of `primary` is logged and the load falls through to `secondary`.
+///   Without a hit, the first error is returned, otherwise a miss.
+/// * A store is attempted on both caches, even if storing to `primary` fails. The first error is
+///   returned.
+pub struct TieredCache<P: Send + Sync, S: Send + Sync> {
+    pub primary: P,
+    pub secondary: S,
+}
+
+/// Error of a [TieredCache], telling which of its caches failed.
+#[derive(Debug)]
+pub enum TieredError<P, S> {
+    Primary(P),
+    Secondary(S),
+}
+
+impl<P: Send + Sync, S: Send + Sync> TieredCache<P, S> {
+    pub fn new(primary: P, secondary: S) -> Self {
+        Self { primary, secondary }
+    }
+    pub fn into_inner(self) -> (P, S) {
+        (self.primary, self.secondary)
+    }
+}
+
+/// Combine the results of storing to both caches, preferring the error of the primary.
+fn first_err<P, S>(
+    primary: Result<(), P>,
+    secondary: Result<(), S>,
+) -> Result<(), TieredError<P, S>> {
+    primary.map_err(TieredError::Primary)?;
+    secondary.map_err(TieredError::Secondary)
+}
+
+/// Combine a failed or missed load from the primary with the load from the secondary.
+fn fallback<T, P, S>(
+    primary: Result<Option<T>, P>,
+    secondary: Result<Option<T>, S>,
+) -> Result<Option<T>, TieredError<P, S>> {
+    match (primary, secondary) {
+        (_, Ok(Some(hit))) => Ok(Some(hit)),
+        (Err(err), _) => Err(TieredError::Primary(err)),
+        (Ok(_), Err(err)) => Err(TieredError::Secondary(err)),
+        (Ok(_), Ok(None)) => Ok(None),
+    }
+}
+
+#[async_trait]
+impl<P: CertCache, S: CertCache> CertCache for TieredCache<P, S>
+where
+    P::EC: Send,
+    S::EC: Send,
+{
+    type EC = TieredError<P::EC, S::EC>;
+    fn is_durable(&self) -> bool {
+        self.primary.is_durable() || self.secondary.is_durable()
+    }
+    async fn load_cert(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+    ) -> Result<Option<Vec<u8>>, Self::EC> {
+        let primary = match self.primary.load_cert(domains, directory_url).await {
+            Ok(Some(cert)) => return Ok(Some(cert)),
+            Ok(None) => Ok(None),
+            Err(err) => {
+                logging::warn!("loading cert from primary cache failed: {:?}", err);
+                Err(err)
+            }
+        };
+        let secondary = self.secondary.load_cert(domains, directory_url).await;
+        if let Ok(Some(cert)) = &secondary {
+            if let Err(err) = self.primary.store_cert(domains, directory_url, cert).await {
+                logging::warn!("copying cert to primary cache failed: {:?}", err);
+            }
+        }
+        fallback(primary, secondary)
+    }
+
+    async fn store_cert(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+        cert: &[u8],
+    ) -> Result<(), Self::EC> {
+        let primary = self.primary.store_cert(domains, directory_url, cert).await;
+        let secondary = self
+            .secondary
+            .store_cert(domains, directory_url, cert)
+            .await;
+        first_err(primary, secondary)
+    }
+
+    async fn remove_cert(&self, domains: &[String], directory_url: &str) -> Result<(), Self::EC> {
+        let primary = self.primary.remove_cert(domains, directory_url).await;
+        let secondary = self.secondary.remove_cert(domains, directory_url).await;
+        first_err(primary, secondary)
+    }
+
+    async fn load_order(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+    ) -> Result<Option<String>, Self::EC> {
+        let primary = match self.primary.load_order(domains, directory_url).await {
+            Ok(Some(order_url)) => return Ok(Some(order_url)),
+            Ok(None) => Ok(None),
+            Err(err) => {
+                logging::warn!("loading order from primary cache failed: {:?}", err);
+                Err(err)
+            }
+        };
+        let secondary = self.secondary.load_order(domains, directory_url).await;
+        fallback(primary, secondary)
+    }
+
+    async fn store_order(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+        order_url: Option<&str>,
+    ) -> Result<(), Self::EC> {
+        let primary = self
+            .primary
+            .store_order(domains, directory_url, order_url)
+            .await;
+        let secondary = self
+            .secondary
+            .store_order(domains, directory_url, order_url)
+            .await;
+        first_err(primary, secondary)
+    }
+}
+
+#[async_trait]
+impl<P: AccountCache, S: AccountCache> AccountCache for TieredCache<P, S>
+where
+    P::EA: Send,
+    S::EA: Send,
+{
+    type EA = TieredError<P::EA, S::EA>;
+    async fn load_account(
+        &self,
+        contact: &[String],
+        directory_url: &str,
+    ) -> Result<Option<Vec<u8>>, Self::EA> {
+        let primary = match self.primary.load_account(contact, directory_url).await {
+            Ok(Some(account)) => return Ok(Some(account)),
+            Ok(None) => Ok(None),
+            Err(err) => {
+                logging::warn!("loading account from primary cache failed: {:?}", err);
+                Err(err)
+            }
+        };
+        let secondary = self.secondary.load_account(contact, directory_url).await;
+        if let Ok(Some(account)) = &secondary {
+            if let Err(err) = self
+                .primary
+                .store_account(contact, directory_url, account)
+                .await
+            {
+                logging::warn!("copying account to primary cache failed: {:?}", err);
+            }
+        }
+        fallback(primary, secondary)
+    }
+
+    async fn store_account(
+        &self,
+        contact: &[String],
+        directory_url: &str,
+        account: &[u8],
+    ) -> Result<(), Self::EA> {
+        let primary = self
+            .primary
+            .store_account(contact, directory_url, account)
+            .await;
+        let secondary = self
+            .secondary
+            .store_account(contact, directory_url, account)
+            .await;
+        first_err(primary, secondary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// A cache holding a single certificate, which records its calls and can be made to fail.
+    #[derive(Default)]
+    struct Backend {
+        fail: bool,
+        cert: Mutex<Option<Vec<u8>>>,
+        calls: Mutex<Vec<&'static str>>,
+    }
+
+    impl Backend {
+        fn failing() -> Self {
+            Self {
+                fail: true,
+                ..Default::default()
+            }
+        }
+        fn with_cert(cert: &[u8]) -> Self {
+            Self {
+                cert: Mutex::new(Some(cert.to_vec())),
+                ..Default::default()
+            }
+        }
+        fn calls(&self) -> Vec<&'static str> {
+            self.calls.lock().unwrap().clone()
+        }
+        fn cert(&self) -> Option<Vec<u8>> {
+            self.cert.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl CertCache for Backend {
+        type EC = &'static str;
+        async fn load_cert(&self, _: &[String], _: &str) -> Result<Option<Vec<u8>>, Self::EC> {
+            self.calls.lock().unwrap().push("load");
+            if self.fail {
+                return Err("load failed");
+            }
+            Ok(self.cert())
+        }
+        async fn store_cert(&self, _: &[String], _: &str, cert: &[u8]) -> Result<(), Self::EC> {
+            self.calls.lock().unwrap().push("store");
+            if self.fail {
+                return Err("store failed");
+            }
+            *self.cert.lock().unwrap() = Some(cert.to_vec());
+            Ok(())
+        }
+    }
+
+    const DIRECTORY_URL: &str = "https://acme.example/directory";
+
+    fn domains() -> Vec<String> {
+        vec!["example.com".to_string()]
+    }
+
+    #[tokio::test]
+    async fn store_is_attempted_on_both_caches() {
+        let cache = TieredCache::new(Backend::failing(), Backend::default());
+        let result = cache.store_cert(&domains(), DIRECTORY_URL, b"cert").await;
+        assert!(matches!(result, Err(TieredError::Primary("store failed"))));
+        assert_eq!(cache.primary.calls(), ["store"]);
+        assert_eq!(cache.secondary.cert().as_deref(), Some(&b"cert"[..]));
+
+        let cache = TieredCache::new(Backend::failing(), Backend::failing());
+        let result = cache.store_cert(&domains(), DIRECTORY_URL, b"cert").await;
+        assert!(matches!(result, Err(TieredError::Primary("store failed"))));
+        assert_eq!(cache.secondary.calls(), ["store"]);
+    }
+
+    #[tokio::test]
+    async fn primary_hit_skips_the_secondary() {
+        let cache = TieredCache::new(Backend::with_cert(b"primary"), Backend::failing());
+        let cert = cache.load_cert(&domains(), DIRECTORY_URL).await.unwrap();
+        assert_eq!(cert.as_deref(), Some(&b"primary"[..]));
+        assert!(cache.secondary.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn secondary_hit_is_copied_to_the_primary() {
+        let cache = TieredCache::new(Backend::default(), Backend::with_cert(b"secondary"));
+        let cert = cache.load_cert(&domains(), DIRECTORY_URL).await.unwrap();
+        assert_eq!(cert.as_deref(), Some(&b"secondary"[..]));
+        assert_eq!(cache.primary.cert().as_deref(), Some(&b"secondary"[..]));
+    }
+
+    #[tokio::test]
+    async fn primary_error_falls_through_to_the_secondary() {
+        let cache = TieredCache::new(Backend::failing(), Backend::with_cert(b"secondary"));
+        let cert = cache.load_cert(&domains(), DIRECTORY_URL).await.unwrap();
+        assert_eq!(cert.as_deref(), Some(&b"secondary"[..]));
+
+        let cache = TieredCache::new(Backend::failing(), Backend::default());
+        let result = cache.load_cert(&domains(), DIRECTORY_URL).await;
+        assert!(matches!(result, Err(TieredError::Primary("load failed"))));
+    }
+}
@@ -0,0 +1,32 @@
+use crate::CertParseError;
+use chrono::{DateTime, TimeZone, Utc};
+use x509_parser::extensions::GeneralName;
+use x509_parser::nom;
+use x509_parser::parse_x509_certificate;
+
+/// Not-before and not-after of a DER encoded certificate.
+pub(crate) fn validity(der: &[u8]) -> Result<[DateTime<Utc>; 2], CertParseError> {
+    let (_, cert) = parse_x509_certificate(der)?;
+    let validity = cert.validity();
+    Ok([validity.not_before, validity.not_after]
+        .map(|t| Utc.timestamp_opt(t.timestamp(), 0).earliest().unwrap()))
+}
+
+/// DNS names in the subject alternative name extension of a DER encoded certificate.
+pub(crate) fn dns_names(der: &[u8]) -> Result<Vec<String>, CertParseError> {
+    let (_, cert) = parse_x509_certificate(der)?;
+    let san = match cert.subject_alternative_name() {
+        Ok(Some(san)) => san,
+        Ok(None) => return Ok(Vec::new()),
+        Err(err) => return Err(CertParseError::X509(nom::Err::Error(err))),
+    };
+    Ok(san
+        .value
+        .general_names
+        .iter()
+        .filter_map(|name| match name {
+            GeneralName::DNSName(name) => Some(name.to_string()),
+            _ => None,
+        })
+        .collect())
+}
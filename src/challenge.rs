@@ -0,0 +1,23 @@
+use crate::acme::ChallengeType;
+use async_trait::async_trait;
+
+/// A challenge the state machine is about to trigger, see
+/// [AcmeState::pending_challenges](crate::AcmeState::pending_challenges).
+#[derive(Clone, Debug)]
+pub struct ChallengeResponse {
+    pub domain: String,
+    pub token: String,
+    /// The key authorization for `token`. For `http-01` it is the expected response body, for
+    /// `dns-01` its base64url encoded SHA-256 digest goes into the TXT record.
+    pub key_authorization: String,
+    pub challenge_type: ChallengeType,
+}
+
+/// Waits for a challenge to be deliverable before the CA is asked to validate it.
+///
+/// Set with [AcmeConfig::challenge_hook](crate::AcmeConfig::challenge_hook).
+#[async_trait]
+pub trait ChallengeHook: Send + Sync {
+    /// Resolve once the response to `challenge` is in place.
+    async fn challenge_ready(&self, challenge: &ChallengeResponse);
+}
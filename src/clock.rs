@@ -0,0 +1,20 @@
+use chrono::{DateTime, Utc};
+
+/// Source of the current time for the validity math of [AcmeState](crate::AcmeState).
+///
+/// Used to decide when a certificate is due for renewal, whether it is ready and whether orders
+/// and authorizations are still usable. Waits still follow tokio's clock, see
+/// [after](crate::after). Set an implementation with [AcmeConfig::clock](crate::AcmeConfig::clock).
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system clock, used by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
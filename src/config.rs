@@ -1,24 +1,144 @@
-use crate::acme::{LETS_ENCRYPT_PRODUCTION_DIRECTORY, LETS_ENCRYPT_STAGING_DIRECTORY};
+use crate::acme::{
+    AccountKeyAlg, AccountKeyPair, ChallengeKey, ChallengeType, HttpsRequestError, ALG,
+    LETS_ENCRYPT_PRODUCTION_DIRECTORY, LETS_ENCRYPT_STAGING_DIRECTORY,
+};
 use crate::caches::{BoxedErrCache, CompositeCache, NoCache};
+use crate::crypto;
+use crate::crypto::backend::error::KeyRejected;
+use crate::crypto::SecretBytes;
+use crate::logging;
+use crate::metrics::NoMetrics;
 use crate::{AccountCache, Cache, CertCache};
-use crate::{AcmeState, Incoming};
+use crate::{
+    AcmeState, ChallengeHook, FallbackCertProvider, Incoming, Metrics, ObtainError, SharedAccount,
+};
+use crate::{Clock, PostDeployHook, PreDeployHook, SystemClock};
+use chrono::{DateTime, Utc};
 use futures::Stream;
-use rustls::{ClientConfig, RootCertStore};
+use rcgen::{CertificateParams, DistinguishedName, ExtendedKeyUsagePurpose, KeyUsagePurpose};
+use rustls::crypto::CryptoProvider;
+use rustls::sign::CertifiedKey;
+use rustls::{ClientConfig, RootCertStore, ServerConfig, SupportedProtocolVersion};
 use std::convert::Infallible;
 use std::fmt::Debug;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite};
 use webpki_roots::TLS_SERVER_ROOTS;
 
+const DEFAULT_USER_AGENT: &str = concat!("tokio-rustls-acme/", env!("CARGO_PKG_VERSION"));
+
+type ChallengeCertParamsFn = dyn Fn(&mut CertificateParams) + Send + Sync;
+
 /// Configuration for an ACME resolver.
 ///
 /// The type parameters represent the error types for the certificate cache and account cache.
+///
+/// Cloning a config is cheap and allows deriving several [AcmeState]s from it, e.g. one per
+/// listener. Clones share the same cache instance, so all states read and write the same
+/// certificate and account entries. Each state still runs its own orders, so states ordering for
+/// the same domains concurrently may issue duplicate certificates, the last one stored winning.
+/// Set a [SharedAccount] before cloning to also share the account key and registration in memory.
 pub struct AcmeConfig<EC: Debug, EA: Debug = EC> {
     pub(crate) client_config: Arc<ClientConfig>,
+    pub(crate) crypto_provider: Arc<CryptoProvider>,
+    pub(crate) root_store: RootCertStore,
     pub(crate) directory_url: String,
+    pub(crate) fallback_directories: Vec<String>,
     pub(crate) domains: Vec<String>,
     pub(crate) contact: Vec<String>,
-    pub(crate) cache: Box<dyn Cache<EC = EC, EA = EA>>,
+    pub(crate) agree_tos: bool,
+    pub(crate) cache: Arc<dyn Cache<EC = EC, EA = EA>>,
+    pub(crate) account_key: Option<SecretBytes>,
+    pub(crate) account_key_alg: AccountKeyAlg,
+    pub(crate) shared_account: Option<SharedAccount>,
+    pub(crate) cert_key: Option<SecretBytes>,
+    pub(crate) proxy: Option<reqwest::Proxy>,
+    pub(crate) http_client: Option<reqwest::Client>,
+    pub(crate) user_agent: String,
+    pub(crate) debug_log_requests: bool,
+    pub(crate) tls_versions: Option<Vec<&'static SupportedProtocolVersion>>,
+    pub(crate) fallback_cert_provider: Option<Arc<dyn FallbackCertProvider>>,
+    pub(crate) metrics: Arc<dyn Metrics>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) challenge_hook: Option<(ChallengeType, Arc<dyn ChallengeHook>)>,
+    pub(crate) challenge_key: Option<Arc<ChallengeKey>>,
+    pub(crate) challenge_cert_params: Option<Arc<ChallengeCertParamsFn>>,
+    pub(crate) pre_deploy_hook: Option<Arc<dyn PreDeployHook>>,
+    pub(crate) post_deploy_hook: Option<Arc<dyn PostDeployHook>>,
+    pub(crate) must_staple: bool,
+    pub(crate) requested_validity: Option<Duration>,
+    pub(crate) distinguished_name: DistinguishedName,
+    pub(crate) key_usages: Vec<KeyUsagePurpose>,
+    pub(crate) extended_key_usages: Vec<ExtendedKeyUsagePurpose>,
+    pub(crate) authorization_poll: (usize, Duration),
+    pub(crate) max_concurrent_authorizations: usize,
+    pub(crate) cache_store_retries: usize,
+    pub(crate) max_order_attempts: Option<usize>,
+    pub(crate) max_issuances: Option<(Duration, usize)>,
+    pub(crate) resume_orders: bool,
+    #[cfg(feature = "caa")]
+    pub(crate) check_caa: bool,
+    pub(crate) processing_poll: (usize, Duration),
+    pub(crate) order_deadline: Option<Duration>,
+    pub(crate) allow_no_cache: bool,
+    pub(crate) require_explicit_directory: bool,
+    pub(crate) has_explicit_directory: bool,
+    #[cfg(feature = "tokio-util")]
+    pub(crate) cancellation_token: Option<tokio_util::sync::CancellationToken>,
+}
+
+impl<EC: Debug, EA: Debug> Clone for AcmeConfig<EC, EA> {
+    fn clone(&self) -> Self {
+        AcmeConfig {
+            client_config: self.client_config.clone(),
+            crypto_provider: self.crypto_provider.clone(),
+            root_store: self.root_store.clone(),
+            directory_url: self.directory_url.clone(),
+            fallback_directories: self.fallback_directories.clone(),
+            domains: self.domains.clone(),
+            contact: self.contact.clone(),
+            agree_tos: self.agree_tos,
+            cache: self.cache.clone(),
+            account_key: self.account_key.clone(),
+            account_key_alg: self.account_key_alg,
+            shared_account: self.shared_account.clone(),
+            cert_key: self.cert_key.clone(),
+            proxy: self.proxy.clone(),
+            http_client: self.http_client.clone(),
+            user_agent: self.user_agent.clone(),
+            debug_log_requests: self.debug_log_requests,
+            tls_versions: self.tls_versions.clone(),
+            fallback_cert_provider: self.fallback_cert_provider.clone(),
+            metrics: self.metrics.clone(),
+            clock: self.clock.clone(),
+            challenge_hook: self.challenge_hook.clone(),
+            challenge_key: self.challenge_key.clone(),
+            challenge_cert_params: self.challenge_cert_params.clone(),
+            pre_deploy_hook: self.pre_deploy_hook.clone(),
+            post_deploy_hook: self.post_deploy_hook.clone(),
+            must_staple: self.must_staple,
+            requested_validity: self.requested_validity,
+            distinguished_name: self.distinguished_name.clone(),
+            key_usages: self.key_usages.clone(),
+            extended_key_usages: self.extended_key_usages.clone(),
+            authorization_poll: self.authorization_poll,
+            max_concurrent_authorizations: self.max_concurrent_authorizations,
+            cache_store_retries: self.cache_store_retries,
+            max_order_attempts: self.max_order_attempts,
+            max_issuances: self.max_issuances,
+            resume_orders: self.resume_orders,
+            #[cfg(feature = "caa")]
+            check_caa: self.check_caa,
+            processing_poll: self.processing_poll,
+            order_deadline: self.order_deadline,
+            allow_no_cache: self.allow_no_cache,
+            require_explicit_directory: self.require_explicit_directory,
+            has_explicit_directory: self.has_explicit_directory,
+            #[cfg(feature = "tokio-util")]
+            cancellation_token: self.cancellation_token.clone(),
+        }
+    }
 }
 
 impl AcmeConfig<Infallible, Infallible> {
@@ -28,6 +148,11 @@ impl AcmeConfig<Infallible, Infallible> {
     /// error types will be `Infallible` since the cache cannot return an error. The methods to set
     /// a cache will change the error types to match those returned by the supplied cache.
     ///
+    /// Internationalized domains like `münchen.example` are converted to their ASCII-compatible
+    /// form (`xn--mnchen-3ya.example`), which is what CAs and TLS clients use. Domains are also
+    /// lowercased and duplicates removed, keeping the order of their first occurrence, so the
+    /// order and the CSR never list the same name twice.
+    ///
     /// ```rust
     /// # use tokio_rustls_acme::AcmeConfig;
     /// use tokio_rustls_acme::caches::DirCache;
@@ -59,17 +184,53 @@ impl AcmeConfig<Infallible, Infallible> {
                     name_constraints: ta.name_constraints.clone(),
                 }),
         );
-        let client_config = Arc::new(
-            ClientConfig::builder()
-                .with_root_certificates(root_store)
-                .with_no_client_auth(),
-        );
         AcmeConfig {
-            client_config,
+            client_config: client_config_with_roots(crypto::default_provider(), &root_store),
+            crypto_provider: crypto::default_provider(),
+            root_store,
             directory_url: LETS_ENCRYPT_STAGING_DIRECTORY.into(),
-            domains: domains.into_iter().map(|s| s.as_ref().into()).collect(),
+            fallback_directories: vec![],
+            domains: normalize_domains(domains),
             contact: vec![],
-            cache: Box::new(NoCache::new()),
+            agree_tos: true,
+            cache: Arc::new(NoCache::new()),
+            account_key: None,
+            account_key_alg: AccountKeyAlg::default(),
+            shared_account: None,
+            cert_key: None,
+            proxy: None,
+            http_client: None,
+            user_agent: DEFAULT_USER_AGENT.into(),
+            debug_log_requests: false,
+            tls_versions: None,
+            fallback_cert_provider: None,
+            metrics: Arc::new(NoMetrics),
+            clock: Arc::new(SystemClock),
+            challenge_hook: None,
+            challenge_key: None,
+            challenge_cert_params: None,
+            pre_deploy_hook: None,
+            post_deploy_hook: None,
+            must_staple: false,
+            requested_validity: None,
+            distinguished_name: DistinguishedName::new(),
+            key_usages: Vec::new(),
+            extended_key_usages: Vec::new(),
+            authorization_poll: (5, Duration::from_secs(1)),
+            max_concurrent_authorizations: 10,
+            cache_store_retries: 2,
+            max_order_attempts: None,
+            max_issuances: None,
+            resume_orders: false,
+            #[cfg(feature = "caa")]
+            check_caa: false,
+            processing_poll: (10, Duration::from_secs(1)),
+            order_deadline: None,
+            allow_no_cache: false,
+            require_explicit_directory: false,
+            has_explicit_directory: false,
+            #[cfg(feature = "tokio-util")]
+            cancellation_token: None,
         }
     }
 }
@@ -80,11 +241,122 @@ impl<EC: 'static + Debug, EA: 'static + Debug> AcmeConfig<EC, EA> {
         self.client_config = client_config;
         self
     }
+
+    /// Trust `cert` in addition to the [webpki_roots] for ACME API calls.
+    ///
+    /// Useful for ACME servers using a private CA, like step-ca. This rebuilds the client config,
+    /// so it replaces a config set with [AcmeConfig::client_tls_config].
+    pub fn add_root_certificate(
+        mut self,
+        cert: rustls::pki_types::CertificateDer<'static>,
+    ) -> Result<Self, rustls::Error> {
+        self.root_store.add(cert)?;
+        self.client_config =
+            client_config_with_roots(self.crypto_provider.clone(), &self.root_store);
+        Ok(self)
+    }
+
+    /// Trust only the roots in `root_store` for ACME API calls, instead of the [webpki_roots].
+    ///
+    /// Like [AcmeConfig::add_root_certificate], this replaces a config set with
+    /// [AcmeConfig::client_tls_config].
+    pub fn with_custom_roots_only(mut self, root_store: RootCertStore) -> Self {
+        self.client_config = client_config_with_roots(self.crypto_provider.clone(), &root_store);
+        self.root_store = root_store;
+        self
+    }
+
+    /// Use `provider` for all TLS connections and certificate keys, instead of the provider of
+    /// the `aws-lc-rs` or `ring` backend.
+    ///
+    /// This covers the ACME API calls, the regular handshakes of [AcmeConfig::incoming] and the
+    /// TLS-ALPN-01 validation requests. The process-wide default provider is never consulted,
+    /// so installing one is not required. Like [AcmeConfig::add_root_certificate], this
+    /// rebuilds the client config and replaces one set with [AcmeConfig::client_tls_config].
+    /// Account keys and the JWS signatures of ACME requests still use the backend directly.
+    pub fn crypto_provider(mut self, provider: Arc<CryptoProvider>) -> Self {
+        self.client_config = client_config_with_roots(provider.clone(), &self.root_store);
+        self.crypto_provider = provider;
+        self
+    }
+    /// Restrict the TLS versions served by [AcmeConfig::incoming] to `versions`, instead of the
+    /// rustls defaults of TLS 1.2 and 1.3.
+    ///
+    /// For example, pass `&[&rustls::version::TLS13]` to serve TLS 1.3 only. TLS-ALPN-01
+    /// validation requests are answered with a config of their own and keep working with either
+    /// version. Fails if `versions` is empty.
+    pub fn tls_versions(
+        mut self,
+        versions: &[&'static SupportedProtocolVersion],
+    ) -> Result<Self, rustls::Error> {
+        crypto::server_config_builder_with_versions(self.crypto_provider.clone(), versions)?;
+        self.tls_versions = Some(versions.to_vec());
+        Ok(self)
+    }
+    /// Set the `User-Agent` of requests to the ACME server, `tokio-rustls-acme/<version>` by
+    /// default.
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+    /// Log every request signed with the account key at debug level, with its URL and the
+    /// decoded protected header and payload.
+    ///
+    /// Meant for debugging requests rejected by a CA. Signatures, the account key and external
+    /// account bindings are never logged. The used nonces, the account URL and the payloads,
+    /// e.g. contacts and CSRs, are. Defaults to `false`.
+    pub fn debug_log_requests(mut self, enabled: bool) -> Self {
+        self.debug_log_requests = enabled;
+        self
+    }
+    /// Send all requests to the ACME server through `proxy`.
+    ///
+    /// By default the proxies configured by the `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY`
+    /// environment variables are used. Setting a proxy disables that detection.
+    pub fn proxy(mut self, proxy: reqwest::Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+    /// Send all requests to the ACME server with `client`, instead of a client built from the
+    /// config.
+    ///
+    /// This gives full control over the HTTP stack, e.g. for custom DNS resolution, client
+    /// certificates towards an egress gateway or middleware signing requests. The TLS config,
+    /// roots, proxy and `User-Agent` set on this config are ignored then, since they only apply
+    /// to the built client.
+    pub fn http_client(mut self, client: reqwest::Client) -> Self {
+        self.http_client = Some(client);
+        self
+    }
     pub fn directory(mut self, directory_url: impl AsRef<str>) -> Self {
         self.directory_url = directory_url.as_ref().into();
+        self.has_explicit_directory = true;
         self
     }
+    /// Directories to try in order if an order at the [AcmeConfig::directory] fails.
+    ///
+    /// After a failed order the next directory is tried after a short delay, and once all have
+    /// failed the usual backoff applies. The directory of the last successful order is used for
+    /// renewals. Certificates are cached per directory, while the account key is cached once,
+    /// under the primary directory, and registered with each CA when first used there. CAs
+    /// requiring external account binding are not supported.
+    pub fn fallback_directories(
+        mut self,
+        directory_urls: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Self {
+        self.fallback_directories = directory_urls
+            .into_iter()
+            .map(|url| url.as_ref().into())
+            .collect();
+        self
+    }
+    /// The primary directory followed by the fallback directories.
+    pub(crate) fn directory_urls(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.directory_url.as_str())
+            .chain(self.fallback_directories.iter().map(String::as_str))
+    }
     pub fn directory_lets_encrypt(mut self, production: bool) -> Self {
+        self.has_explicit_directory = true;
         self.directory_url = match production {
             true => LETS_ENCRYPT_PRODUCTION_DIRECTORY,
             false => LETS_ENCRYPT_STAGING_DIRECTORY,
@@ -92,38 +364,384 @@ impl<EC: 'static + Debug, EA: 'static + Debug> AcmeConfig<EC, EA> {
         .into();
         self
     }
-    pub fn domains(mut self, contact: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
-        self.domains = contact.into_iter().map(|s| s.as_ref().into()).collect();
+    /// Replace the domains, normalized as described in [AcmeConfig::new].
+    pub fn domains(mut self, domains: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        self.domains = normalize_domains(domains);
         self
     }
-    pub fn domains_push(mut self, contact: impl AsRef<str>) -> Self {
-        self.domains.push(contact.as_ref().into());
+    /// Add a domain, unless it is already listed after normalization.
+    pub fn domains_push(mut self, domain: impl AsRef<str>) -> Self {
+        let domain = normalize_domain(domain.as_ref());
+        if !self.domains.contains(&domain) {
+            self.domains.push(domain);
+        }
         self
     }
 
     /// Provide a list of contacts for the account.
     ///
-    /// Note that email addresses must include a `mailto:` prefix.
+    /// Contacts are URIs, email addresses should include a `mailto:` prefix. Contacts starting
+    /// with a scheme, such as `mailto:`, `tel:` or a custom scheme accepted by a private CA, are
+    /// passed to the CA verbatim. A bare email address is prefixed with `mailto:` automatically.
     pub fn contact(mut self, contact: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
-        self.contact = contact.into_iter().map(|s| s.as_ref().into()).collect();
+        self.contact = contact
+            .into_iter()
+            .map(|s| normalize_contact(s.as_ref()))
+            .collect();
         self
     }
 
     /// Provide a contact for the account.
     ///
-    /// Note that an email address must include a `mailto:` prefix.
+    /// See [AcmeConfig::contact] for how the contact is validated.
     pub fn contact_push(mut self, contact: impl AsRef<str>) -> Self {
-        self.contact.push(contact.as_ref().into());
+        self.contact.push(normalize_contact(contact.as_ref()));
+        self
+    }
+
+    /// Whether to agree to the terms of service of the CA when creating an account, defaults to
+    /// `true`.
+    ///
+    /// Without agreement, only an existing account for the account key is used. If there is none,
+    /// orders fail with [AcmeError::TosNotAgreed] carrying the terms of service URL announced by
+    /// the directory, so consent can be obtained before creating the account.
+    ///
+    /// [AcmeError::TosNotAgreed]: crate::acme::AcmeError::TosNotAgreed
+    pub fn agree_tos(mut self, agree: bool) -> Self {
+        self.agree_tos = agree;
+        self
+    }
+
+    /// Use an externally managed account key instead of loading or generating one.
+    ///
+    /// The key must be a PKCS#8 DER encoded ECDSA P-256 or Ed25519 key pair. It is validated
+    /// immediately and an error is returned if it is rejected. When set, the account cache is
+    /// neither loaded from nor stored to.
+    pub fn account_key(mut self, key_pair: Vec<u8>) -> Result<Self, KeyRejected> {
+        AccountKeyPair::from_pkcs8(&key_pair)?;
+        self.account_key = Some(key_pair.into());
+        Ok(self)
+    }
+
+    /// The algorithm of newly generated account keys, ECDSA P-256 by default.
+    ///
+    /// Keys loaded from the cache are used with their own algorithm.
+    pub fn account_key_alg(mut self, alg: AccountKeyAlg) -> Self {
+        self.account_key_alg = alg;
+        self
+    }
+
+    /// Share the ACME account with other configs holding a clone of `account`.
+    ///
+    /// See [SharedAccount] for details.
+    pub fn shared_account(mut self, account: SharedAccount) -> Self {
+        self.shared_account = Some(account);
+        self
+    }
+
+    /// Use a fixed certificate key for every order instead of generating a new one each time.
+    ///
+    /// The key must be a PKCS#8 DER encoded ECDSA P-256 key pair, it is validated immediately.
+    /// Keys of other curves or algorithms, e.g. P-384 or Ed25519, are rejected, as the key is
+    /// always loaded for ECDSA P-256 with SHA-256. This keeps the public key of the certificate
+    /// stable across renewals, e.g. for key pinning. A cached certificate with a different key is
+    /// still deployed, but renewed right away.
+    pub fn cert_key(mut self, key_pair: Vec<u8>) -> Result<Self, KeyRejected> {
+        crypto::ecdsa_from_pkcs8(ALG, &key_pair)?;
+        self.cert_key = Some(key_pair.into());
+        Ok(self)
+    }
+
+    /// Request certificates with the OCSP Must-Staple TLS Feature extension
+    /// ([RFC 7633](https://www.rfc-editor.org/rfc/rfc7633)).
+    ///
+    /// The extension is only added to the CSR and has to be supported by the CA. Clients that
+    /// honor it reject the certificate unless the server staples an OCSP response, which this crate
+    /// does not do on its own.
+    pub fn must_staple(mut self, must_staple: bool) -> Self {
+        self.must_staple = must_staple;
+        self
+    }
+
+    /// Stop the [AcmeState] and [Incoming] streams once `token` is cancelled.
+    ///
+    /// After cancellation both streams yield `None`. Any in-flight order, cache operation and
+    /// pending TLS handshake is dropped.
+    #[cfg(feature = "tokio-util")]
+    pub fn cancellation_token(mut self, token: tokio_util::sync::CancellationToken) -> Self {
+        self.cancellation_token = Some(token);
+        self
+    }
+
+    /// Configure how often and how fast pending authorizations are polled.
+    ///
+    /// The delay before each poll starts at `base_delay` and doubles with every attempt. If the
+    /// authorization is still pending after `attempts` polls, the order fails. Defaults to 5
+    /// attempts starting at 1 second.
+    pub fn authorization_poll(mut self, attempts: usize, base_delay: Duration) -> Self {
+        self.authorization_poll = (attempts, base_delay);
+        self
+    }
+
+    /// Store the URL of an order in flight in the cert cache and resume it after a restart.
+    ///
+    /// A stored order is only resumed while it is pending or ready, so that authorizations that
+    /// were already completed are reused. Otherwise a new order is created. Requires a cache
+    /// implementing [CertCache::load_order](crate::CertCache::load_order) and
+    /// [CertCache::store_order](crate::CertCache::store_order), like
+    /// [DirCache](crate::caches::DirCache).
+    pub fn resume_orders(mut self, resume: bool) -> Self {
+        self.resume_orders = resume;
+        self
+    }
+
+    /// Check the CAA records of the domains before each order.
+    ///
+    /// If the CAA records of a domain allow none of the `caaIdentities` advertised by the
+    /// directory, the order fails early with [OrderError::CaaForbidden] instead of at the
+    /// authorization. DNS lookup failures skip the check.
+    ///
+    /// [OrderError::CaaForbidden]: crate::OrderError::CaaForbidden
+    #[cfg(feature = "caa")]
+    pub fn check_caa(mut self, check: bool) -> Self {
+        self.check_caa = check;
+        self
+    }
+
+    /// Limit how many authorizations of an order are completed at the same time. Defaults to 10.
+    pub fn max_concurrent_authorizations(mut self, max: usize) -> Self {
+        self.max_concurrent_authorizations = max.max(1);
+        self
+    }
+
+    /// Give up after `max` consecutive failed orders, instead of retrying forever.
+    ///
+    /// The [AcmeState] then yields [EventError::OrdersExhausted] with the last error and ends,
+    /// which lets short-lived jobs fail with a proper exit code. Defaults to `None`.
+    ///
+    /// [EventError::OrdersExhausted]: crate::EventError::OrdersExhausted
+    pub fn max_order_attempts(mut self, max: Option<usize>) -> Self {
+        self.max_order_attempts = max.map(|max| max.max(1));
+        self
+    }
+
+    /// Send at most `count` finalize requests, each issuing a certificate, within any `window`.
+    ///
+    /// This is a local guardrail below the rate limits of the CA. Once reached, no new order is
+    /// started and the [AcmeState] yields [EventError::IssuanceCapReached] and waits until the
+    /// oldest issuance leaves the window. Issuances are counted per state and in memory only, so
+    /// restarts and other states are not taken into account. Defaults to no cap.
+    ///
+    /// [EventError::IssuanceCapReached]: crate::EventError::IssuanceCapReached
+    pub fn max_issuances_per(mut self, window: Duration, count: usize) -> Self {
+        self.max_issuances = Some((window, count.max(1)));
+        self
+    }
+
+    /// Retry storing a new certificate or account key in the cache up to `retries` times, with
+    /// exponential backoff starting at one second. Defaults to 2.
+    ///
+    /// A store failure is not fatal, the new certificate is served anyway. But it is lost on
+    /// restart, costing another order against the rate limits of the CA, and a lost account key
+    /// creates a new account. The error is produced as an event once all retries failed.
+    pub fn cache_store_retries(mut self, retries: usize) -> Self {
+        self.cache_store_retries = retries;
+        self
+    }
+
+    /// Configure how often and how fast an order in the processing state is polled.
+    ///
+    /// The delay before each poll starts at `base_delay` and doubles with every attempt. If the
+    /// order is still processing after `attempts` polls, the order fails. Defaults to 10
+    /// attempts starting at 1 second.
+    pub fn processing_poll(mut self, attempts: usize, base_delay: Duration) -> Self {
+        self.processing_poll = (attempts, base_delay);
+        self
+    }
+
+    /// Cancel an order that has not completed within `deadline`, e.g. because the CA stopped
+    /// responding or an authorization stays pending.
+    ///
+    /// The order then fails with [OrderError::DeadlineExceeded](crate::OrderError) and is retried
+    /// after the usual backoff. The deadline covers the whole order, including account creation
+    /// and the certificate download. Defaults to no deadline.
+    pub fn order_deadline(mut self, deadline: Duration) -> Self {
+        self.order_deadline = Some(deadline);
+        self
+    }
+
+    /// Ask the CA to issue certificates valid for `valid_for`, starting when the order is placed.
+    ///
+    /// This sends the `notBefore` and `notAfter` fields with every new order. Many CAs, including
+    /// Let's Encrypt, ignore them or reject orders that contain them. A duration is taken instead
+    /// of fixed `notBefore` and `notAfter` timestamps, because the same config places an order at
+    /// every renewal, and fixed timestamps would already have passed by the first renewal.
+    /// [Account::new_order_with_validity](crate::acme::Account::new_order_with_validity) takes
+    /// fixed timestamps for single orders.
+    pub fn requested_validity(mut self, valid_for: Duration) -> Self {
+        self.requested_validity = Some(valid_for);
+        self
+    }
+
+    /// Set the subject of the certificate signing requests, which is empty by default.
+    ///
+    /// The subject alternative names are always taken from the configured domains. Public CAs
+    /// like Let's Encrypt drop most subject attributes, so this is mainly useful with private ACME
+    /// CAs.
+    pub fn distinguished_name(mut self, distinguished_name: DistinguishedName) -> Self {
+        self.distinguished_name = distinguished_name;
+        self
+    }
+
+    /// Request key usages in the certificate signing requests, none by default.
+    ///
+    /// Like [AcmeConfig::distinguished_name], this is meant for private ACME CAs. Public CAs
+    /// ignore the requested usages and apply their own profile.
+    pub fn key_usages(mut self, key_usages: Vec<KeyUsagePurpose>) -> Self {
+        self.key_usages = key_usages;
+        self
+    }
+
+    /// Request extended key usages in the certificate signing requests, none by default.
+    ///
+    /// For example, a private CA may issue certificates usable for mTLS with
+    /// [ExtendedKeyUsagePurpose::ClientAuth] in addition to
+    /// [ExtendedKeyUsagePurpose::ServerAuth]. Public CAs ignore the requested usages.
+    pub fn extended_key_usages(
+        mut self,
+        extended_key_usages: Vec<ExtendedKeyUsagePurpose>,
+    ) -> Self {
+        self.extended_key_usages = extended_key_usages;
+        self
+    }
+
+    /// Serve certificates from `provider` as long as no ACME certificate is available.
+    ///
+    /// See [FallbackCertProvider] for details.
+    pub fn fallback_cert_provider(mut self, provider: impl FallbackCertProvider + 'static) -> Self {
+        self.fallback_cert_provider = Some(Arc::new(provider));
+        self
+    }
+
+    /// Complete `challenge_type` challenges and wait for `hook` before each is triggered.
+    ///
+    /// This allows delivering challenge responses by other means, e.g. uploading `http-01`
+    /// responses to a CDN. For `tls-alpn-01` the built-in delivery through [AcmeAcceptor] is still
+    /// used, the hook can delay validation until all servers are ready to answer it.
+    ///
+    /// [AcmeAcceptor]: crate::AcmeAcceptor
+    pub fn challenge_hook(
+        mut self,
+        challenge_type: ChallengeType,
+        hook: impl ChallengeHook + 'static,
+    ) -> Self {
+        self.challenge_hook = Some((challenge_type, Arc::new(hook)));
+        self
+    }
+
+    /// Run `hook` with every newly issued certificate before it is served, e.g. to push it to
+    /// sidecars first.
+    ///
+    /// If the hook fails, the certificate is discarded and the order fails with
+    /// [OrderError::PreDeployHook](crate::OrderError::PreDeployHook). The next order then
+    /// issues another certificate after the usual backoff, which counts towards the rate limits
+    /// of the CA. Certificates loaded from the cache are deployed without the hook.
+    pub fn pre_deploy_hook(mut self, hook: impl PreDeployHook + 'static) -> Self {
+        self.pre_deploy_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Run `hook` with every newly issued certificate once it is served, e.g. to reload
+    /// dependent services.
+    ///
+    /// The hook runs before the certificate is stored in the cache. Certificates loaded from the
+    /// cache are deployed without the hook.
+    pub fn post_deploy_hook(mut self, hook: impl PostDeployHook + 'static) -> Self {
+        self.post_deploy_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Sign tls-alpn-01 challenge certificates with the PKCS#8 DER encoded ECDSA P-256 `key_pair`.
+    ///
+    /// By default a key pair is generated for the first challenge and reused for later ones.
+    pub fn challenge_key(mut self, key_pair: &[u8]) -> Result<Self, rcgen::Error> {
+        self.challenge_key = Some(Arc::new(ChallengeKey::from_pkcs8(key_pair)?));
+        Ok(self)
+    }
+
+    /// Customize the parameters of tls-alpn-01 challenge certificates, e.g. their validity or
+    /// distinguished name.
+    ///
+    /// `f` is called with parameters for the validated domain. The `acmeIdentifier` extension
+    /// is added afterwards, and the subject alternative names must not be changed.
+    pub fn challenge_cert_params(
+        mut self,
+        f: impl Fn(&mut CertificateParams) + Send + Sync + 'static,
+    ) -> Self {
+        self.challenge_cert_params = Some(Arc::new(f));
+        self
+    }
+
+    /// Report the progress of orders and deployed certificates to `metrics`.
+    pub fn metrics(mut self, metrics: impl Metrics + 'static) -> Self {
+        self.metrics = Arc::new(metrics);
+        self
+    }
+
+    /// Take the current time from `clock` instead of the system clock, e.g. to test renewals.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
         self
     }
 
     pub fn cache<C: 'static + Cache>(self, cache: C) -> AcmeConfig<C::EC, C::EA> {
         AcmeConfig {
             client_config: self.client_config,
+            crypto_provider: self.crypto_provider,
+            root_store: self.root_store,
             directory_url: self.directory_url,
+            fallback_directories: self.fallback_directories,
             domains: self.domains,
             contact: self.contact,
-            cache: Box::new(cache),
+            agree_tos: self.agree_tos,
+            cache: Arc::new(cache),
+            account_key: self.account_key,
+            account_key_alg: self.account_key_alg,
+            shared_account: self.shared_account,
+            cert_key: self.cert_key,
+            proxy: self.proxy,
+            http_client: self.http_client,
+            user_agent: self.user_agent,
+            debug_log_requests: self.debug_log_requests,
+            tls_versions: self.tls_versions,
+            fallback_cert_provider: self.fallback_cert_provider,
+            metrics: self.metrics,
+            clock: self.clock,
+            challenge_hook: self.challenge_hook,
+            challenge_key: self.challenge_key,
+            challenge_cert_params: self.challenge_cert_params,
+            pre_deploy_hook: self.pre_deploy_hook,
+            post_deploy_hook: self.post_deploy_hook,
+            must_staple: self.must_staple,
+            requested_validity: self.requested_validity,
+            distinguished_name: self.distinguished_name,
+            key_usages: self.key_usages,
+            extended_key_usages: self.extended_key_usages,
+            authorization_poll: self.authorization_poll,
+            max_concurrent_authorizations: self.max_concurrent_authorizations,
+            cache_store_retries: self.cache_store_retries,
+            max_order_attempts: self.max_order_attempts,
+            max_issuances: self.max_issuances,
+            resume_orders: self.resume_orders,
+            #[cfg(feature = "caa")]
+            check_caa: self.check_caa,
+            processing_poll: self.processing_poll,
+            order_deadline: self.order_deadline,
+            allow_no_cache: self.allow_no_cache,
+            require_explicit_directory: self.require_explicit_directory,
+            has_explicit_directory: self.has_explicit_directory,
+            #[cfg(feature = "tokio-util")]
+            cancellation_token: self.cancellation_token,
         }
     }
     pub fn cache_compose<CC: 'static + CertCache, CA: 'static + AccountCache>(
@@ -142,6 +760,60 @@ impl<EC: 'static + Debug, EA: 'static + Debug> AcmeConfig<EC, EA> {
             None => self.cache(NoCache::<C::EC, C::EA>::new()),
         }
     }
+    /// Stop caching certificates, while keeping the account cache set so far.
+    ///
+    /// Every start then orders a new certificate with the same account. Like a config without
+    /// any cache, this requires [AcmeConfig::allow_no_cache] for the Let's Encrypt production
+    /// directory.
+    pub fn no_cert_cache(mut self) -> Self {
+        let account_cache = self.cache.clone();
+        self.cache = Arc::new(CompositeCache::new(NoCache::<EC, EA>::new(), account_cache));
+        self
+    }
+    /// Stop caching the account key, while keeping the certificate cache set so far.
+    ///
+    /// A new account is then created by the first order after every start, unless an account key
+    /// is set with [AcmeConfig::account_key].
+    pub fn no_account_cache(mut self) -> Self {
+        let cert_cache = self.cache.clone();
+        self.cache = Arc::new(CompositeCache::new(cert_cache, NoCache::<EC, EA>::new()));
+        self
+    }
+    /// Allow using the Let's Encrypt production directory without a cache.
+    ///
+    /// Without a cache every restart orders a new certificate, which quickly exhausts the
+    /// production rate limits. Therefore the [AcmeState] refuses to start with
+    /// [ConfigError::NoCacheInProduction] unless a cache was set or this method was called.
+    ///
+    /// [ConfigError::NoCacheInProduction]: crate::ConfigError::NoCacheInProduction
+    pub fn allow_no_cache(mut self) -> Self {
+        self.allow_no_cache = true;
+        self
+    }
+    /// Refuse to start unless a directory was chosen with [AcmeConfig::directory] or
+    /// [AcmeConfig::directory_lets_encrypt].
+    ///
+    /// This guards against deploying with the default staging directory by accident, which
+    /// issues untrusted certificates. The [AcmeState] stops immediately with
+    /// [ConfigError::NoExplicitDirectory] if no directory was chosen.
+    ///
+    /// [ConfigError::NoExplicitDirectory]: crate::ConfigError::NoExplicitDirectory
+    pub fn require_explicit_directory(mut self) -> Self {
+        self.require_explicit_directory = true;
+        self
+    }
+    pub(crate) fn client(&self) -> Result<reqwest::Client, HttpsRequestError> {
+        if let Some(client) = &self.http_client {
+            return Ok(client.clone());
+        }
+        let mut builder = reqwest::ClientBuilder::new()
+            .use_preconfigured_tls((*self.client_config).clone())
+            .user_agent(&self.user_agent);
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy.clone());
+        }
+        Ok(builder.build()?)
+    }
     pub fn state(self) -> AcmeState<EC, EA> {
         AcmeState::new(self)
     }
@@ -159,4 +831,152 @@ impl<EC: 'static + Debug, EA: 'static + Debug> AcmeConfig<EC, EA> {
     ) -> Incoming<TCP, ETCP, ITCP, EC, EA> {
         self.state().incoming(tcp_incoming, alpn_protocols)
     }
+    /// Obtain a certificate once, answering validation requests on `tcp_incoming`, see
+    /// [AcmeState::obtain_certificate].
+    pub async fn obtain_certificate<
+        TCP: AsyncRead + AsyncWrite + Unpin,
+        ETCP,
+        ITCP: Stream<Item = Result<TCP, ETCP>> + Unpin,
+    >(
+        self,
+        tcp_incoming: ITCP,
+    ) -> Result<(Arc<CertifiedKey>, [DateTime<Utc>; 2]), ObtainError<EC, EA>> {
+        self.state().obtain_certificate(tcp_incoming).await
+    }
+    /// Turn a stream of TCP connections into a stream of TLS connections served with a custom
+    /// `server_config`.
+    ///
+    /// This allows to, e.g., customize the TLS versions or cipher suites. See
+    /// [Incoming::with_server_config] for how `server_config` and `alpn_protocols` are merged.
+    pub fn incoming_with_server<
+        TCP: AsyncRead + AsyncWrite + Unpin,
+        ETCP,
+        ITCP: Stream<Item = Result<TCP, ETCP>> + Unpin,
+    >(
+        self,
+        tcp_incoming: ITCP,
+        server_config: ServerConfig,
+        alpn_protocols: Vec<Vec<u8>>,
+    ) -> Incoming<TCP, ETCP, ITCP, EC, EA> {
+        self.state()
+            .incoming_with_server(tcp_incoming, server_config, alpn_protocols)
+    }
+}
+
+/// Normalize `domains` and remove duplicates, keeping the first occurrence.
+fn normalize_domains(domains: impl IntoIterator<Item = impl AsRef<str>>) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for domain in domains {
+        let domain = normalize_domain(domain.as_ref());
+        if normalized.contains(&domain) {
+            logging::warn!("ignoring duplicate domain {:?}", domain);
+        } else {
+            normalized.push(domain);
+        }
+    }
+    normalized
+}
+
+/// Convert internationalized domains to their ASCII-compatible (`xn--`) form, which CAs expect,
+/// and ASCII domains to lowercase.
+fn normalize_domain(domain: &str) -> String {
+    if domain.is_ascii() {
+        return domain.to_ascii_lowercase();
+    }
+    let (wildcard, name) = match domain.strip_prefix("*.") {
+        Some(name) => ("*.", name),
+        None => ("", domain),
+    };
+    match idna::domain_to_ascii(name) {
+        Ok(name) => format!("{}{}", wildcard, name),
+        Err(err) => {
+            logging::warn!("could not convert domain {:?} to ASCII: {}", domain, err);
+            domain.into()
+        }
+    }
+}
+
+fn normalize_contact(contact: &str) -> String {
+    if has_uri_scheme(contact) {
+        return contact.into();
+    }
+    let looks_like_email = !contact.contains(':')
+        && !contact.contains(char::is_whitespace)
+        && matches!(contact.split_once('@'), Some((local, domain)) if !local.is_empty() && !domain.is_empty());
+    if looks_like_email {
+        logging::warn!(
+            "contact {:?} is missing the mailto: prefix, adding it",
+            contact
+        );
+        return format!("mailto:{}", contact);
+    }
+    logging::warn!(
+        "contact {:?} is neither a URI nor an email address and will likely be rejected",
+        contact
+    );
+    contact.into()
+}
+
+/// Whether `uri` starts with a scheme as defined by RFC 3986, e.g. `mailto:` or `tel:`.
+fn has_uri_scheme(uri: &str) -> bool {
+    match uri.split_once(':') {
+        Some((scheme, _)) => {
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+        }
+        None => false,
+    }
+}
+
+fn client_config_with_roots(
+    provider: Arc<CryptoProvider>,
+    root_store: &RootCertStore,
+) -> Arc<ClientConfig> {
+    Arc::new(
+        crypto::client_config_builder(provider)
+            .with_root_certificates(root_store.clone())
+            .with_no_client_auth(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unicode_domains_round_trip_through_punycode() {
+        let config = AcmeConfig::new(["münchen.example", "*.MÜNCHEN.example", "example.com"]);
+        assert_eq!(
+            config.domains,
+            [
+                "xn--mnchen-3ya.example",
+                "*.xn--mnchen-3ya.example",
+                "example.com"
+            ]
+        );
+        assert_eq!(
+            idna::domain_to_unicode(&config.domains[0]).0,
+            "münchen.example"
+        );
+        let domains = config.domains.clone();
+        let config = config.domains(domains);
+        assert_eq!(config.domains[0], "xn--mnchen-3ya.example");
+    }
+
+    #[test]
+    fn contacts_with_a_scheme_are_kept() {
+        for contact in [
+            "tel:+1-555-0100",
+            "mailto:admin@example.com",
+            "https://example.com/c",
+        ] {
+            assert_eq!(normalize_contact(contact), contact);
+        }
+        assert_eq!(
+            normalize_contact("admin@example.com"),
+            "mailto:admin@example.com"
+        );
+    }
 }
@@ -0,0 +1,99 @@
+//! Selects the crypto backend. If both the `ring` and `aws-lc-rs` features are enabled,
+//! aws-lc-rs is used.
+
+use rustls::crypto::CryptoProvider;
+use rustls::server::WantsServerCert;
+use rustls::{ClientConfig, ConfigBuilder, ServerConfig, SupportedProtocolVersion, WantsVerifier};
+use std::sync::Arc;
+
+#[cfg(not(any(feature = "ring", feature = "aws-lc-rs")))]
+compile_error!("either the `ring` or the `aws-lc-rs` feature must be enabled");
+
+#[cfg(feature = "aws-lc-rs")]
+pub(crate) use aws_lc_rs as backend;
+#[cfg(all(feature = "ring", not(feature = "aws-lc-rs")))]
+pub(crate) use ring as backend;
+
+#[cfg(feature = "aws-lc-rs")]
+pub(crate) use rustls::crypto::aws_lc_rs as rustls_backend;
+#[cfg(all(feature = "ring", not(feature = "aws-lc-rs")))]
+pub(crate) use rustls::crypto::ring as rustls_backend;
+
+use backend::error::KeyRejected;
+use backend::signature::{EcdsaKeyPair, EcdsaSigningAlgorithm};
+
+/// The provider of the selected backend, used unless one is set with
+/// [AcmeConfig::crypto_provider](crate::AcmeConfig::crypto_provider).
+pub(crate) fn default_provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls_backend::default_provider())
+}
+
+/// Like [ServerConfig::builder], but with `provider` instead of the process-wide default.
+pub(crate) fn server_config_builder(
+    provider: Arc<CryptoProvider>,
+) -> ConfigBuilder<ServerConfig, WantsServerCert> {
+    ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+}
+
+/// Like [server_config_builder], but restricted to the protocol `versions`.
+pub(crate) fn server_config_builder_with_versions(
+    provider: Arc<CryptoProvider>,
+    versions: &[&'static SupportedProtocolVersion],
+) -> Result<ConfigBuilder<ServerConfig, WantsServerCert>, rustls::Error> {
+    Ok(ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(versions)?
+        .with_no_client_auth())
+}
+
+/// Like [ClientConfig::builder], but with `provider` instead of the process-wide default.
+pub(crate) fn client_config_builder(
+    provider: Arc<CryptoProvider>,
+) -> ConfigBuilder<ClientConfig, WantsVerifier> {
+    ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+}
+
+pub(crate) fn ecdsa_from_pkcs8(
+    alg: &'static EcdsaSigningAlgorithm,
+    pkcs8: &[u8],
+) -> Result<EcdsaKeyPair, KeyRejected> {
+    #[cfg(feature = "aws-lc-rs")]
+    return EcdsaKeyPair::from_pkcs8(alg, pkcs8);
+    #[cfg(not(feature = "aws-lc-rs"))]
+    return EcdsaKeyPair::from_pkcs8(alg, pkcs8, &backend::rand::SystemRandom::new());
+}
+
+/// Private key material, which is zeroed when dropped if the `zeroize` feature is enabled.
+#[derive(Clone)]
+pub struct SecretBytes(Vec<u8>);
+
+impl std::fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecretBytes").finish_non_exhaustive()
+    }
+}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+}
+
+impl std::ops::Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        #[cfg(feature = "zeroize")]
+        zeroize::Zeroize::zeroize(&mut self.0);
+    }
+}
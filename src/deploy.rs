@@ -0,0 +1,28 @@
+use async_trait::async_trait;
+use rustls::sign::CertifiedKey;
+use std::error::Error;
+
+/// Runs before a newly issued certificate is served.
+///
+/// Set with [AcmeConfig::pre_deploy_hook](crate::AcmeConfig::pre_deploy_hook).
+#[async_trait]
+pub trait PreDeployHook: Send + Sync {
+    /// Prepare the deployment of `cert`, whose key and chain are in `pem`.
+    ///
+    /// An error aborts the deployment, see
+    /// [AcmeConfig::pre_deploy_hook](crate::AcmeConfig::pre_deploy_hook).
+    async fn pre_deploy(
+        &self,
+        cert: &CertifiedKey,
+        pem: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
+}
+
+/// Runs after a newly issued certificate is served.
+///
+/// Set with [AcmeConfig::post_deploy_hook](crate::AcmeConfig::post_deploy_hook).
+#[async_trait]
+pub trait PostDeployHook: Send + Sync {
+    /// Complete the deployment of `cert`, whose key and chain are in `pem`.
+    async fn post_deploy(&self, cert: &CertifiedKey, pem: &[u8]);
+}
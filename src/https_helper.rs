@@ -1,4 +1,8 @@
-use rustls::{pki_types::InvalidDnsNameError, ClientConfig};
+use crate::acme::Problem;
+use chrono::{DateTime, Utc};
+use rustls::pki_types::InvalidDnsNameError;
+use std::sync::Mutex;
+use std::time::Duration;
 use thiserror::Error;
 
 pub use reqwest::Response;
@@ -20,17 +24,39 @@ impl From<Method> for reqwest::Method {
     }
 }
 
+/// Nonces received with responses, to sign later requests without fetching a new one first.
+#[derive(Debug, Default)]
+pub(crate) struct NoncePool(Mutex<Vec<String>>);
+
+impl NoncePool {
+    /// Nonces beyond this are dropped, a request usually returns the nonce for the next one.
+    const CAPACITY: usize = 8;
+
+    pub(crate) fn pop(&self) -> Option<String> {
+        self.0.lock().unwrap().pop()
+    }
+    fn push(&self, nonce: String) {
+        let mut nonces = self.0.lock().unwrap();
+        if nonces.len() < Self::CAPACITY {
+            nonces.push(nonce);
+        }
+    }
+}
+
+/// Send a request, adding the `Replay-Nonce` of the response to `nonces`, also for errors.
 pub(crate) async fn https(
-    client_config: &ClientConfig,
+    client: &reqwest::Client,
     url: impl AsRef<str>,
     method: Method,
     body: Option<String>,
+    accept: Option<&str>,
+    nonces: Option<&NoncePool>,
 ) -> Result<Response, HttpsRequestError> {
     let method: reqwest::Method = method.into();
-    let client = reqwest::ClientBuilder::new()
-        .use_preconfigured_tls(client_config.clone())
-        .build()?;
     let mut request = client.request(method, url.as_ref());
+    if let Some(accept) = accept {
+        request = request.header("Accept", accept);
+    }
     if let Some(body) = body {
         request = request
             .body(body)
@@ -38,16 +64,48 @@ pub(crate) async fn https(
     }
 
     let response = request.send().await?;
+    if let Some(nonces) = nonces {
+        let nonce = response.headers().get("Replay-Nonce");
+        if let Some(nonce) = nonce.and_then(|nonce| nonce.to_str().ok()) {
+            nonces.push(nonce.to_string());
+        }
+    }
     let status = response.status();
     if !status.is_success() {
+        let retry_after = response
+            .headers()
+            .get("Retry-After")
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_retry_after);
+        let body = response.text().await?;
+        if let Ok(problem) = serde_json::from_str::<Problem>(&body) {
+            if problem.typ.as_deref() == Some(RATE_LIMITED_PROBLEM_TYPE) {
+                return Err(HttpsRequestError::RateLimited {
+                    retry_after,
+                    detail: problem.detail,
+                });
+            }
+        }
         return Err(HttpsRequestError::Non2xxStatus {
             status_code: status.into(),
-            body: response.text().await?,
+            body,
         });
     }
     Ok(response)
 }
 
+const RATE_LIMITED_PROBLEM_TYPE: &str = "urn:ietf:params:acme:error:rateLimited";
+
+/// Parses a `Retry-After` header value, which is either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
 impl From<reqwest::Error> for HttpsRequestError {
     fn from(e: reqwest::Error) -> Self {
         Self::Http(e.into())
@@ -64,6 +122,79 @@ pub enum HttpsRequestError {
     Http(Box<dyn std::error::Error + Send + Sync + 'static>),
     #[error("non 2xx http status: {status_code} {body:?}")]
     Non2xxStatus { status_code: u16, body: String },
+    #[error("rate limited by acme service, retry after {retry_after:?}: {detail:?}")]
+    RateLimited {
+        retry_after: Option<Duration>,
+        detail: Option<String>,
+    },
     #[error("could not determine host from url")]
     UndefinedHost,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{header, StatusCode};
+    use axum::response::IntoResponse;
+    use axum::routing::post;
+    use axum::Router;
+
+    #[test]
+    fn retry_after_accepts_seconds() {
+        assert_eq!(parse_retry_after(" 120 "), Some(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn retry_after_accepts_an_http_date() {
+        let date = (Utc::now() + chrono::Duration::hours(1)).to_rfc2822();
+        let retry_after = parse_retry_after(&date).unwrap();
+        assert!(retry_after > Duration::from_secs(3590));
+        assert!(retry_after <= Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn retry_after_in_the_past_is_ignored() {
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[tokio::test]
+    async fn too_many_requests_is_reported_as_rate_limited() {
+        async fn new_order() -> impl IntoResponse {
+            let problem = serde_json::json!({
+                "type": RATE_LIMITED_PROBLEM_TYPE,
+                "detail": "too many new orders",
+            });
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, "30")],
+                problem.to_string(),
+            )
+        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/new-order", listener.local_addr().unwrap());
+        let router = Router::new().route("/new-order", post(new_order));
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let err = https(
+            &reqwest::Client::new(),
+            url,
+            Method::Post,
+            Some("{}".into()),
+            None,
+            None,
+        )
+        .await
+        .unwrap_err();
+        match err {
+            HttpsRequestError::RateLimited {
+                retry_after,
+                detail,
+            } => {
+                assert_eq!(retry_after, Some(Duration::from_secs(30)));
+                assert_eq!(detail.as_deref(), Some("too many new orders"));
+            }
+            err => panic!("expected a rate limit error, got {:?}", err),
+        }
+    }
+}
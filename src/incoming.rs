@@ -1,15 +1,35 @@
-use crate::acceptor::{AcmeAccept, AcmeAcceptor};
+use crate::acceptor::{AcceptedOrValidation, AcmeAccept, AcmeAcceptor};
+use crate::crypto;
+use crate::logging;
 use crate::AcmeState;
+use futures::future::poll_fn;
 use futures::stream::{FusedStream, FuturesUnordered};
 use futures::Stream;
 use rustls::ServerConfig;
 use std::fmt::Debug;
+use std::io;
+use std::ops::{Deref, DerefMut};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
-use tokio::io::{AsyncRead, AsyncWrite};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio_rustls::{server::TlsStream, Accept};
 
+/// Stream of TLS connections, driving an [AcmeState] and answering validation requests on the side.
+///
+/// The yielded [TlsConnection]s wrap regular [TlsStream]s. If a client disconnects without sending a TLS
+/// `close_notify` alert, reads fail with [std::io::ErrorKind::UnexpectedEof] instead of returning
+/// 0. rustls has no option to tolerate this, since it cannot tell a clean close from a truncation
+/// attack. Protocols that frame their messages, like HTTP with a `Content-Length`, can safely
+/// treat this error as the end of the stream.
+///
+/// An `Err` from `tcp_incoming`, like `EMFILE` when running out of file descriptors, is yielded
+/// as is and does not end the stream. The [AcmeState], pending handshakes and the listener keep
+/// running, and the next poll accepts from `tcp_incoming` again. Failed handshakes are only
+/// logged. The stream ends when the [AcmeState] ends, or once `tcp_incoming` has ended and all
+/// pending handshakes have completed. Accept loops should therefore skip `Err` items, possibly
+/// after a short delay, rather than stop on them.
 pub struct Incoming<
     TCP: AsyncRead + AsyncWrite + Unpin,
     ETCP,
@@ -49,19 +69,101 @@ impl<
         acceptor: AcmeAcceptor,
         alpn_protocols: Vec<Vec<u8>>,
     ) -> Self {
-        let mut config = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_cert_resolver(state.resolver());
-        config.alpn_protocols = alpn_protocols;
+        let provider = state.crypto_provider();
+        let builder = match state.tls_versions() {
+            Some(versions) => crypto::server_config_builder_with_versions(provider, versions)
+                .expect("versions are checked by AcmeConfig::tls_versions"),
+            None => crypto::server_config_builder(provider),
+        };
+        let config = builder.with_cert_resolver(state.resolver());
+        Self::with_server_config(tcp_incoming, state, acceptor, config, alpn_protocols)
+    }
+
+    /// Like [Incoming::new], but finishes regular handshakes with a custom `server_config`.
+    ///
+    /// The certificate resolver of `server_config` is replaced by the ACME resolver of `state`.
+    /// The ALPN protocols of `server_config` take precedence, protocols from `alpn_protocols`
+    /// that it does not list yet are appended in order. The `acme-tls/1` protocol does not need to
+    /// be included, since validation requests are answered by `acceptor` with its own config.
+    pub fn with_server_config(
+        tcp_incoming: ITCP,
+        state: AcmeState<EC, EA>,
+        acceptor: AcmeAcceptor,
+        mut server_config: ServerConfig,
+        alpn_protocols: Vec<Vec<u8>>,
+    ) -> Self {
+        server_config.cert_resolver = state.resolver();
+        for protocol in alpn_protocols {
+            if !server_config.alpn_protocols.contains(&protocol) {
+                server_config.alpn_protocols.push(protocol);
+            }
+        }
         Self {
             state,
             acceptor,
-            rustls_config: Arc::new(config),
+            rustls_config: Arc::new(server_config),
             tcp_incoming: Some(tcp_incoming),
             acme_accepting: FuturesUnordered::new(),
             tls_accepting: FuturesUnordered::new(),
         }
     }
+
+    /// Stop serving connections, but drive an in-flight order to completion first.
+    ///
+    /// Pending handshakes are dropped. TCP connections keep being accepted, but only to answer
+    /// TLS-ALPN-01 validation requests, regular connections are closed right away. Resolves once
+    /// the order has completed and its certificate is cached, or when `timeout` has elapsed.
+    pub async fn shutdown(mut self, timeout: Duration) {
+        self.tls_accepting.clear();
+        let drain = poll_fn(|cx| self.poll_shutdown(cx));
+        if tokio::time::timeout(timeout, drain).await.is_err() {
+            logging::warn!("shutdown timed out, abandoning the order in flight");
+        }
+    }
+
+    fn poll_shutdown(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            if !self.state.has_pending_work() {
+                return Poll::Ready(());
+            }
+            match Pin::new(&mut self.state).poll_next(cx) {
+                Poll::Ready(Some(event)) => {
+                    match event {
+                        Ok(ok) => logging::info!("event: {:?}", ok),
+                        Err(err) => logging::error!("event: {:?}", err),
+                    }
+                    continue;
+                }
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => {}
+            }
+            match Pin::new(&mut self.acme_accepting).poll_next(cx) {
+                Poll::Ready(Some(Ok(AcceptedOrValidation::Accepted(_)))) => continue,
+                Poll::Ready(Some(Ok(AcceptedOrValidation::Validation))) => {
+                    logging::info!("received TLS-ALPN-01 validation request");
+                    continue;
+                }
+                Poll::Ready(Some(Err(err))) => {
+                    logging::error!("tls accept failed, {:?}", err);
+                    continue;
+                }
+                Poll::Ready(None) | Poll::Pending => {}
+            }
+            let tcp_incoming = match &mut self.tcp_incoming {
+                Some(tcp_incoming) => tcp_incoming,
+                None => return Poll::Pending,
+            };
+            match Pin::new(tcp_incoming).poll_next(cx) {
+                Poll::Ready(Some(Ok(tcp))) => self.acme_accepting.push(self.acceptor.accept(tcp)),
+                Poll::Ready(Some(Err(_))) => {
+                    logging::error!("tcp accept failed during shutdown");
+                    continue;
+                }
+                Poll::Ready(None) => drop(self.tcp_incoming.take()),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
 }
 
 impl<
@@ -72,39 +174,46 @@ impl<
         EA: Debug + 'static,
     > Stream for Incoming<TCP, ETCP, ITCP, EC, EA>
 {
-    type Item = Result<TlsStream<TCP>, ETCP>;
+    type Item = Result<TlsConnection<TCP>, ETCP>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         loop {
             match Pin::new(&mut self.state).poll_next(cx) {
                 Poll::Ready(Some(event)) => {
                     match event {
-                        Ok(ok) => log::info!("event: {:?}", ok),
-                        Err(err) => log::error!("event: {:?}", err),
+                        Ok(ok) => logging::info!("event: {:?}", ok),
+                        Err(err) => logging::error!("event: {:?}", err),
                     }
                     continue;
                 }
-                Poll::Ready(None) => unreachable!(),
+                Poll::Ready(None) => {
+                    self.tcp_incoming = None;
+                    self.acme_accepting.clear();
+                    self.tls_accepting.clear();
+                    return Poll::Ready(None);
+                }
                 Poll::Pending => {}
             }
             match Pin::new(&mut self.acme_accepting).poll_next(cx) {
-                Poll::Ready(Some(Ok(Some(tls)))) => self
+                Poll::Ready(Some(Ok(AcceptedOrValidation::Accepted(tls)))) => self
                     .tls_accepting
                     .push(tls.into_stream(self.rustls_config.clone())),
-                Poll::Ready(Some(Ok(None))) => {
-                    log::info!("received TLS-ALPN-01 validation request");
+                Poll::Ready(Some(Ok(AcceptedOrValidation::Validation))) => {
+                    logging::info!("received TLS-ALPN-01 validation request");
                     continue;
                 }
                 Poll::Ready(Some(Err(err))) => {
-                    log::error!("tls accept failed, {:?}", err);
+                    logging::error!("tls accept failed, {:?}", err);
                     continue;
                 }
                 Poll::Ready(None) | Poll::Pending => {}
             }
             match Pin::new(&mut self.tls_accepting).poll_next(cx) {
-                Poll::Ready(Some(Ok(tls))) => return Poll::Ready(Some(Ok(tls))),
+                Poll::Ready(Some(Ok(tls))) => {
+                    return Poll::Ready(Some(Ok(TlsConnection { inner: tls })))
+                }
                 Poll::Ready(Some(Err(err))) => {
-                    log::error!("tls accept failed, {:?}", err);
+                    logging::error!("tls accept failed, {:?}", err);
                     continue;
                 }
                 Poll::Ready(None) | Poll::Pending => {}
@@ -119,7 +228,7 @@ impl<
             match Pin::new(tcp_incoming).poll_next(cx) {
                 Poll::Ready(Some(Ok(tcp))) => self.acme_accepting.push(self.acceptor.accept(tcp)),
                 Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
-                Poll::Ready(None) => drop(self.tcp_incoming.as_mut().take()),
+                Poll::Ready(None) => drop(self.tcp_incoming.take()),
                 Poll::Pending => return Poll::Pending,
             }
         }
@@ -135,8 +244,169 @@ impl<
     > FusedStream for Incoming<TCP, ETCP, ITCP, EC, EA>
 {
     fn is_terminated(&self) -> bool {
+        if self.state.is_terminated() {
+            return true;
+        }
         self.tcp_incoming.is_none()
             && self.acme_accepting.is_terminated()
             && self.tls_accepting.is_terminated()
     }
 }
+
+/// A TLS connection yielded by [Incoming], with accessors for the negotiated handshake parameters.
+///
+/// Dereferences to the underlying [TlsStream] and delegates IO to it.
+#[derive(Debug)]
+pub struct TlsConnection<TCP> {
+    inner: TlsStream<TCP>,
+}
+
+impl<TCP> TlsConnection<TCP> {
+    /// The server name sent by the client with SNI, if any.
+    pub fn server_name(&self) -> Option<&str> {
+        self.inner.get_ref().1.server_name()
+    }
+
+    /// The negotiated ALPN protocol, if any.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.inner.get_ref().1.alpn_protocol()
+    }
+
+    pub fn into_inner(self) -> TlsStream<TCP> {
+        self.inner
+    }
+}
+
+impl<TCP> Deref for TlsConnection<TCP> {
+    type Target = TlsStream<TCP>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<TCP> DerefMut for TlsConnection<TCP> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl<TCP> From<TlsConnection<TCP>> for TlsStream<TCP> {
+    fn from(connection: TlsConnection<TCP>) -> Self {
+        connection.inner
+    }
+}
+
+impl<TCP: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsConnection<TCP> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<TCP: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsConnection<TCP> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acme::ACME_TLS_ALPN_NAME;
+    use crate::crypto;
+    use crate::crypto::rustls_backend::sign::any_ecdsa_type;
+    use crate::AcmeConfig;
+    use futures::StreamExt;
+    use rustls::pki_types::{PrivatePkcs8KeyDer, ServerName};
+    use rustls::sign::CertifiedKey;
+    use rustls::RootCertStore;
+    use std::convert::TryFrom;
+    use std::io;
+    use std::time::Duration;
+    use tokio_rustls::TlsConnector;
+
+    #[tokio::test]
+    async fn with_server_config_merges_alpn_and_answers_validations() {
+        // The directory is never answered, so only the validation request is served.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let state = AcmeConfig::new(["example.com"])
+            .directory(format!(
+                "http://{}/directory",
+                listener.local_addr().unwrap()
+            ))
+            .state();
+
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let params = rcgen::CertificateParams::new(vec!["example.com".to_string()]).unwrap();
+        let cert = params.self_signed(&key_pair).unwrap();
+        let key = PrivatePkcs8KeyDer::from(key_pair.serialize_der()).into();
+        let auth_key = CertifiedKey::new(vec![cert.der().clone()], any_ecdsa_type(&key).unwrap());
+        state
+            .resolver()
+            .set_auth_key("example.com".into(), Arc::new(auth_key));
+
+        let mut server_config = crypto::server_config_builder(crypto::default_provider())
+            .with_cert_resolver(state.resolver());
+        server_config.alpn_protocols = vec![b"h2".to_vec()];
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let tcp_incoming =
+            futures::stream::iter([Ok::<_, io::Error>(server)]).chain(futures::stream::pending());
+        let acceptor = state.acceptor();
+        let alpn_protocols = vec![b"http/1.1".to_vec(), b"h2".to_vec()];
+        let mut incoming = Incoming::with_server_config(
+            tcp_incoming,
+            state,
+            acceptor,
+            server_config,
+            alpn_protocols,
+        );
+        assert_eq!(
+            incoming.rustls_config.alpn_protocols,
+            [b"h2".to_vec(), b"http/1.1".to_vec()]
+        );
+        tokio::spawn(async move { while incoming.next().await.is_some() {} });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.der().clone()).unwrap();
+        let mut client_config = crypto::client_config_builder(crypto::default_provider())
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![ACME_TLS_ALPN_NAME.to_vec()];
+        let server_name = ServerName::try_from("example.com").unwrap();
+        let connect = TlsConnector::from(Arc::new(client_config)).connect(server_name, client);
+        let tls = tokio::time::timeout(Duration::from_secs(10), connect)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(tls.get_ref().1.alpn_protocol(), Some(ACME_TLS_ALPN_NAME));
+        drop(listener);
+    }
+}
@@ -1,13 +1,13 @@
+use crate::acme::AccountKeyPair;
+use crate::crypto::backend::digest::{digest, Digest, SHA256};
+use crate::crypto::backend::signature::KeyPair;
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine;
-use ring::digest::{digest, Digest, SHA256};
-use ring::rand::SystemRandom;
-use ring::signature::{EcdsaKeyPair, KeyPair};
 use serde::Serialize;
 use thiserror::Error;
 
 pub(crate) fn sign(
-    key: &EcdsaKeyPair,
+    key: &AccountKeyPair,
     kid: Option<&str>,
     nonce: String,
     url: &str,
@@ -17,11 +17,11 @@ pub(crate) fn sign(
         None => Some(Jwk::new(key)),
         Some(_) => None,
     };
-    let protected = Protected::base64(jwk, kid, nonce, url)?;
+    let protected = Protected::base64(jws_alg(key), jwk, kid, nonce, url)?;
     let payload = URL_SAFE_NO_PAD.encode(payload);
     let combined = format!("{}.{}", &protected, &payload);
-    let signature = key.sign(&SystemRandom::new(), combined.as_bytes())?;
-    let signature = URL_SAFE_NO_PAD.encode(signature.as_ref());
+    let signature = key.sign(combined.as_bytes())?;
+    let signature = URL_SAFE_NO_PAD.encode(signature);
     let body = Body {
         protected,
         payload,
@@ -31,14 +31,68 @@ pub(crate) fn sign(
 }
 
 pub(crate) fn key_authorization_sha256(
-    key: &EcdsaKeyPair,
+    key: &AccountKeyPair,
     token: &str,
 ) -> Result<Digest, JoseError> {
-    let jwk = Jwk::new(key);
-    let key_authorization = format!("{}.{}", token, jwk.thumb_sha256_base64()?);
+    let key_authorization = key_authorization(key, token)?;
     Ok(digest(&SHA256, key_authorization.as_bytes()))
 }
 
+pub(crate) fn key_authorization(key: &AccountKeyPair, token: &str) -> Result<String, JoseError> {
+    let jwk = Jwk::new(key);
+    Ok(format!("{}.{}", token, jwk.thumb_sha256_base64()?))
+}
+
+pub(crate) fn jwk(key: &AccountKeyPair) -> serde_json::Value {
+    serde_json::to_value(Jwk::new(key)).expect("jwk is serializable")
+}
+
+pub(crate) fn thumbprint(key: &AccountKeyPair) -> String {
+    Jwk::new(key)
+        .thumb_sha256_base64()
+        .expect("jwk is serializable")
+}
+
+fn jws_alg(key: &AccountKeyPair) -> &'static str {
+    match key {
+        AccountKeyPair::EcdsaP256(_) => "ES256",
+        AccountKeyPair::Ed25519(_) => "EdDSA",
+    }
+}
+
+/// The decoded protected header and payload of the JWS `body`, for debug logs.
+///
+/// The signature is left out, and so are the key in the protected header and an external account
+/// binding in the payload, which are replaced by `"<redacted>"`.
+pub(crate) fn redacted(body: &str) -> String {
+    let decode = |value: &serde_json::Value| -> Option<serde_json::Value> {
+        let decoded = URL_SAFE_NO_PAD.decode(value.as_str()?).ok()?;
+        match decoded.is_empty() {
+            true => Some(serde_json::Value::String(String::new())),
+            false => serde_json::from_slice(&decoded).ok(),
+        }
+    };
+    let body: serde_json::Value = match serde_json::from_str(body) {
+        Ok(body) => body,
+        Err(_) => return "<not a JWS>".into(),
+    };
+    let mut protected = body.get("protected").and_then(decode);
+    let mut payload = body.get("payload").and_then(decode);
+    for (value, field) in [
+        (&mut protected, "jwk"),
+        (&mut payload, "externalAccountBinding"),
+    ] {
+        if let Some(entry) = value.as_mut().and_then(|v| v.get_mut(field)) {
+            *entry = "<redacted>".into();
+        }
+    }
+    let show = |value: Option<serde_json::Value>| match value {
+        Some(value) => value.to_string(),
+        None => "<undecodable>".into(),
+    };
+    format!("protected: {}, payload: {}", show(protected), show(payload))
+}
+
 #[derive(Serialize)]
 struct Body {
     protected: String,
@@ -59,13 +113,14 @@ struct Protected<'a> {
 
 impl<'a> Protected<'a> {
     fn base64(
+        alg: &'static str,
         jwk: Option<Jwk>,
         kid: Option<&'a str>,
         nonce: String,
         url: &'a str,
     ) -> Result<String, JoseError> {
         let protected = Self {
-            alg: "ES256",
+            alg,
             jwk,
             kid,
             nonce,
@@ -84,19 +139,32 @@ struct Jwk {
     #[serde(rename = "use")]
     u: &'static str,
     x: String,
-    y: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    y: Option<String>,
 }
 
 impl Jwk {
-    pub(crate) fn new(key: &EcdsaKeyPair) -> Self {
-        let (x, y) = key.public_key().as_ref()[1..].split_at(32);
-        Self {
-            alg: "ES256",
-            crv: "P-256",
-            kty: "EC",
-            u: "sig",
-            x: URL_SAFE_NO_PAD.encode(x),
-            y: URL_SAFE_NO_PAD.encode(y),
+    pub(crate) fn new(key: &AccountKeyPair) -> Self {
+        match key {
+            AccountKeyPair::EcdsaP256(key) => {
+                let (x, y) = key.public_key().as_ref()[1..].split_at(32);
+                Self {
+                    alg: "ES256",
+                    crv: "P-256",
+                    kty: "EC",
+                    u: "sig",
+                    x: URL_SAFE_NO_PAD.encode(x),
+                    y: Some(URL_SAFE_NO_PAD.encode(y)),
+                }
+            }
+            AccountKeyPair::Ed25519(key) => Self {
+                alg: "EdDSA",
+                crv: "Ed25519",
+                kty: "OKP",
+                u: "sig",
+                x: URL_SAFE_NO_PAD.encode(key.public_key()),
+                y: None,
+            },
         }
     }
     pub(crate) fn thumb_sha256_base64(&self) -> Result<String, JoseError> {
@@ -104,7 +172,7 @@ impl Jwk {
             crv: self.crv,
             kty: self.kty,
             x: &self.x,
-            y: &self.y,
+            y: self.y.as_deref(),
         };
         let json = serde_json::to_vec(&jwk_thumb)?;
         let hash = digest(&SHA256, &json);
@@ -117,7 +185,8 @@ struct JwkThumb<'a> {
     crv: &'a str,
     kty: &'a str,
     x: &'a str,
-    y: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    y: Option<&'a str>,
 }
 
 #[derive(Error, Debug)]
@@ -125,5 +194,63 @@ pub enum JoseError {
     #[error("json serialization failed: {0}")]
     Json(#[from] serde_json::Error),
     #[error("crypto error: {0}")]
-    Crypto(#[from] ring::error::Unspecified),
+    Crypto(#[from] crate::crypto::backend::error::Unspecified),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acme::{Account, AccountKeyAlg};
+    use crate::crypto::backend::signature::{
+        UnparsedPublicKey, VerificationAlgorithm, ECDSA_P256_SHA256_FIXED, ED25519,
+    };
+
+    fn decode(value: &serde_json::Value) -> Vec<u8> {
+        URL_SAFE_NO_PAD.decode(value.as_str().unwrap()).unwrap()
+    }
+
+    fn sign_and_verify(alg: AccountKeyAlg) {
+        let pkcs8 = Account::generate_key_pair_with_alg(alg);
+        let key = AccountKeyPair::from_pkcs8(&pkcs8).unwrap();
+        let url = "https://acme.example/new-account";
+        let body = sign(&key, None, "nonce".into(), url, "{}").unwrap();
+
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let protected: serde_json::Value =
+            serde_json::from_slice(&decode(&body["protected"])).unwrap();
+        assert_eq!(protected["alg"], jws_alg(&key));
+        assert_eq!(protected["url"], url);
+        let jwk = &protected["jwk"];
+        let (public_key, verification_alg): (_, &'static dyn VerificationAlgorithm) =
+            match jwk["kty"].as_str().unwrap() {
+                "EC" => {
+                    assert_eq!(jwk["crv"], "P-256");
+                    let point = [&[4][..], &decode(&jwk["x"]), &decode(&jwk["y"])].concat();
+                    (point, &ECDSA_P256_SHA256_FIXED)
+                }
+                "OKP" => {
+                    assert_eq!(jwk["crv"], "Ed25519");
+                    (decode(&jwk["x"]), &ED25519)
+                }
+                kty => panic!("unexpected kty {}", kty),
+            };
+        let message = format!(
+            "{}.{}",
+            body["protected"].as_str().unwrap(),
+            body["payload"].as_str().unwrap()
+        );
+        UnparsedPublicKey::new(verification_alg, public_key)
+            .verify(message.as_bytes(), &decode(&body["signature"]))
+            .unwrap();
+    }
+
+    #[test]
+    fn es256_signature_verifies_against_jwk() {
+        sign_and_verify(AccountKeyAlg::EcdsaP256);
+    }
+
+    #[test]
+    fn eddsa_signature_verifies_against_jwk() {
+        sign_and_verify(AccountKeyAlg::Ed25519);
+    }
 }
@@ -11,9 +11,12 @@
 //! The goal is to provide a [Let's Encrypt](https://letsencrypt.org/) compatible TLS serving and
 //! certificate management using a simple and flexible stream based API.
 //!
-//! This crate uses [ring] as [rustls]'s backend, instead of [aws-lc-rs]. This generally makes it
-//! much easier to compile. If you'd like to use [aws-lc-rs] as [rustls]'s backend, we're open to
-//! contributions with the necessary `Cargo.toml` changes and feature-flags to enable you to do so.
+//! By default this crate uses [ring] as [rustls]'s backend, instead of [aws-lc-rs]. This generally
+//! makes it much easier to compile. To use [aws-lc-rs] instead, disable default features and
+//! enable the `aws-lc-rs` feature. If both features are enabled, [aws-lc-rs] is used.
+//!
+//! With the `zeroize` feature, the account and certificate private keys held by this crate are
+//! zeroed in memory when dropped.
 //!
 //! To use tokio-rustls-acme add the following lines to your `Cargo.toml`:
 //!
@@ -78,16 +81,33 @@
 //! directly, the library exposes the underlying certificate management [AcmeState] as well as a
 //! matching resolver [ResolvesServerCertAcme] which implements the [rustls::server::ResolvesServerCert] trait.
 //! See the server_low_level example on how to use the low-level API directly with [tokio_rustls].
+//! With the `driver` feature, `AcmeState::spawn_driver` drives the state on a task of its own.
+//! With the `caa` feature, `AcmeConfig::check_caa` looks up the CAA records of the domains before
+//! each order and fails early if they do not allow the CA to issue.
+//! With the `self-check` feature, `AcmeState::self_check` sends a TLS-ALPN-01 validation request
+//! to each domain, to find unreachable listeners before the CA does.
+//!
+//! To obtain the first certificate before serving any traffic, call [AcmeState::bootstrap] with a
+//! listener that only answers TLS-ALPN-01 validation requests. The [ResolvesServerCertAcme] and
+//! [AcmeAcceptor] of the state are the challenge solver. Both can be wired to any number of
+//! listeners and keep working when the state is handed to the serving path afterwards.
 //!
 //! ## Account and certificate caching
 //!
 //! A production server using the let's encrypt production directory must implement both account and
 //! certificate caching to avoid exhausting the let's encrypt API rate limits.
-//! A file based cache using a cache directory is provided by [caches::DirCache].
+//! For this reason an [AcmeState] configured for the production directory without a cache stops
+//! immediately with [ConfigError::NoCacheInProduction], unless [AcmeConfig::allow_no_cache] was
+//! called.
+//! A file based cache using a cache directory is provided by [caches::DirCache]. With the `sqlite`
+//! feature, `caches::SqliteCache` stores everything in a single SQLite database instead.
 //! Caches backed by other persistence layers may be implemented using the [Cache] trait,
 //! or the underlying [CertCache], [AccountCache] traits (contributions welcome).
 //! [caches::CompositeCache] provides a wrapper to combine two implementors of [CertCache] and
 //! [AccountCache] into a single [Cache].
+//! The contents of a [caches::DirCache] can be backed up and restored using [caches::archive].
+//! Cache entries start with a header line naming the version of their format, entries without it
+//! are read as written by earlier releases.
 //!
 //! Note, that the error type parameters of the cache carries over to some other types in this
 //! crate via the [AcmeConfig] they are added to.
@@ -123,20 +143,44 @@ mod acceptor;
 pub mod acme;
 #[cfg(feature = "axum")]
 pub mod axum;
+#[cfg(feature = "caa")]
+mod caa;
 mod cache;
 pub mod caches;
+mod certificate;
+mod challenge;
+mod clock;
 mod config;
+mod crypto;
+mod deploy;
 mod https_helper;
 mod incoming;
 mod jose;
+mod logging;
+mod metrics;
+#[cfg(test)]
+mod mock_acme;
 mod resolver;
+#[cfg(feature = "self-check")]
+mod self_check;
+mod shared_account;
 mod state;
 
+pub use rcgen;
+pub use reqwest;
 pub use tokio_rustls;
 
 pub use acceptor::*;
 pub use cache::*;
+pub use challenge::*;
+pub use clock::*;
 pub use config::*;
+pub use crypto::SecretBytes;
+pub use deploy::*;
 pub use incoming::*;
+pub use metrics::*;
 pub use resolver::*;
+#[cfg(feature = "self-check")]
+pub use self_check::SelfCheckError;
+pub use shared_account::*;
 pub use state::*;
@@ -0,0 +1,6 @@
+//! Log through `tracing` instead of `log` if the `tracing` feature is enabled.
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) use log::{debug, error, info, warn};
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::{debug, error, info, warn};
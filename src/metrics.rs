@@ -0,0 +1,32 @@
+use crate::OrderError;
+use chrono::{DateTime, Utc};
+
+/// Instrumentation hooks called by [AcmeState](crate::AcmeState) as orders progress.
+///
+/// All methods have empty default implementations, so implementors only override what they
+/// need. Set an implementation with [AcmeConfig::metrics](crate::AcmeConfig::metrics).
+pub trait Metrics: Send + Sync {
+    /// A new order for `domains` is placed at `directory_url`. `attempt` counts the consecutive
+    /// failed orders before this one.
+    fn order_started(&self, domains: &[String], directory_url: &str, attempt: usize) {
+        let _ = (domains, directory_url, attempt);
+    }
+    /// The order completed and a certificate was issued.
+    fn order_succeeded(&self) {}
+    /// The order failed, it is retried after a backoff.
+    fn order_failed(&self, err: &OrderError) {
+        let _ = err;
+    }
+    /// The authorization for `domain` is valid.
+    fn authorization_completed(&self, domain: &str) {
+        let _ = domain;
+    }
+    /// A certificate, either loaded from the cache or newly issued, was deployed.
+    fn cert_deployed(&self, not_after: DateTime<Utc>) {
+        let _ = not_after;
+    }
+}
+
+pub(crate) struct NoMetrics;
+
+impl Metrics for NoMetrics {}
@@ -0,0 +1,210 @@
+//! A minimal ACME server for tests, served over plain http.
+//!
+//! Orders are ready right away, without authorizations, so no challenge has to be answered, and
+//! stay ready until they are finalized. There is a single order url, [MockAcme::order_url]. The
+//! issued certificate is for [MockAcme::cert_key], which has to be set with
+//! [AcmeConfig::cert_key](crate::AcmeConfig::cert_key).
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{HeaderMap, Method, StatusCode, Uri};
+use axum::response::Response;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use std::sync::{Arc, Mutex};
+
+/// How the mock answers the finalize request.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Finalize {
+    /// The order is valid right away, with its certificate url.
+    Valid,
+    /// The order is valid right away, but the certificate url only comes with the next fetch.
+    ValidWithoutCertificate,
+}
+
+#[derive(Clone)]
+pub(crate) struct MockOptions {
+    /// Send the `Replay-Nonce` header with `HEAD` requests to `newNonce`, not only with `GET`.
+    pub(crate) nonce_on_head: bool,
+    pub(crate) finalize: Finalize,
+}
+
+impl Default for MockOptions {
+    fn default() -> Self {
+        Self {
+            nonce_on_head: true,
+            finalize: Finalize::Valid,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct MockRequest {
+    pub(crate) method: Method,
+    pub(crate) path: String,
+    pub(crate) user_agent: Option<String>,
+    pub(crate) body: String,
+}
+
+pub(crate) struct MockAcme {
+    url: String,
+    cert_key: Vec<u8>,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+}
+
+struct Server {
+    url: String,
+    options: MockOptions,
+    chain: String,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+}
+
+impl MockAcme {
+    pub(crate) async fn start(options: MockOptions) -> Self {
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+            .await
+            .unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let params = rcgen::CertificateParams::new(vec!["example.com".to_string()]).unwrap();
+        let chain = params.self_signed(&key_pair).unwrap().pem();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let server = Arc::new(Server {
+            url: url.clone(),
+            options,
+            chain,
+            requests: requests.clone(),
+        });
+        let router = axum::Router::new().fallback(handle).with_state(server);
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        Self {
+            url,
+            cert_key: key_pair.serialize_der(),
+            requests,
+        }
+    }
+    pub(crate) fn directory_url(&self) -> String {
+        format!("{}/directory", self.url)
+    }
+    /// The url of the order, created by every `newOrder` request.
+    pub(crate) fn order_url(&self) -> String {
+        format!("{}/order", self.url)
+    }
+    /// The url of the certificate issued for an order.
+    pub(crate) fn certificate_url(&self) -> String {
+        format!("{}/cert", self.url)
+    }
+    /// The PKCS#8 DER encoded key the issued certificate is for.
+    pub(crate) fn cert_key(&self) -> Vec<u8> {
+        self.cert_key.clone()
+    }
+    pub(crate) fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+    /// The decoded JWS payloads of all requests to `path`.
+    pub(crate) fn payloads(&self, path: &str) -> Vec<serde_json::Value> {
+        let requests = self.requests().into_iter();
+        requests
+            .filter(|request| request.path == path)
+            .map(|request| {
+                let jws: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+                let payload = URL_SAFE_NO_PAD.decode(jws["payload"].as_str().unwrap());
+                serde_json::from_slice(&payload.unwrap()).unwrap()
+            })
+            .collect()
+    }
+    /// The DER encoded CSRs of all finalize requests.
+    pub(crate) fn csrs(&self) -> Vec<Vec<u8>> {
+        let payloads = self.payloads("/finalize").into_iter();
+        payloads
+            .map(|payload| URL_SAFE_NO_PAD.decode(payload["csr"].as_str().unwrap()))
+            .map(Result::unwrap)
+            .collect()
+    }
+    pub(crate) fn requested(&self, path: &str) -> bool {
+        self.requests().iter().any(|request| request.path == path)
+    }
+}
+
+async fn handle(
+    State(server): State<Arc<Server>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    let path = uri.path().to_string();
+    let user_agent = headers
+        .get("User-Agent")
+        .and_then(|user_agent| user_agent.to_str().ok())
+        .map(String::from);
+    let finalized = {
+        let mut requests = server.requests.lock().unwrap();
+        requests.push(MockRequest {
+            method: method.clone(),
+            path: path.clone(),
+            user_agent,
+            body,
+        });
+        requests.iter().any(|request| request.path == "/finalize")
+    };
+    let url = &server.url;
+    let order = |status: &str, certificate: bool| {
+        let mut order = serde_json::json!({
+            "status": status,
+            "authorizations": [],
+            "finalize": format!("{}/finalize", url),
+        });
+        if certificate {
+            order["certificate"] = format!("{}/cert", url).into();
+        }
+        order.to_string()
+    };
+    let (status, location, body) = match (method.as_str(), path.as_str()) {
+        ("GET", "/directory") => {
+            let directory = serde_json::json!({
+                "newNonce": format!("{}/nonce", url),
+                "newAccount": format!("{}/new-account", url),
+                "newOrder": format!("{}/new-order", url),
+            });
+            (StatusCode::OK, None, directory.to_string())
+        }
+        ("HEAD", "/nonce") if !server.options.nonce_on_head => {
+            return Response::new(Body::empty());
+        }
+        ("HEAD" | "GET", "/nonce") => (StatusCode::OK, None, String::new()),
+        ("POST", "/new-account") => {
+            let location = format!("{}/account", url);
+            (
+                StatusCode::CREATED,
+                Some(location),
+                r#"{"status":"valid"}"#.into(),
+            )
+        }
+        ("POST", "/new-order") => {
+            let location = format!("{}/order", url);
+            (StatusCode::CREATED, Some(location), order("ready", false))
+        }
+        ("POST", "/finalize") => {
+            let certificate = server.options.finalize == Finalize::Valid;
+            (StatusCode::OK, None, order("valid", certificate))
+        }
+        ("POST", "/order") if !finalized => (StatusCode::OK, None, order("ready", false)),
+        ("POST", "/order") => (StatusCode::OK, None, order("valid", true)),
+        ("POST", "/cert") => (StatusCode::OK, None, server.chain.clone()),
+        _ => (StatusCode::NOT_FOUND, None, String::new()),
+    };
+    let mut response = Response::builder()
+        .status(status)
+        .header("Replay-Nonce", format!("nonce-{}", next_nonce()));
+    if let Some(location) = location {
+        response = response.header("Location", location);
+    }
+    response.body(Body::from(body)).unwrap()
+}
+
+fn next_nonce() -> u64 {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static NONCE: AtomicU64 = AtomicU64::new(0);
+    NONCE.fetch_add(1, Ordering::Relaxed)
+}
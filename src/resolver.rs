@@ -1,35 +1,123 @@
-use crate::acme::ACME_TLS_ALPN_NAME;
+use crate::acme::{ChallengeKey, ACME_TLS_ALPN_NAME};
+use crate::certificate;
+use crate::logging;
+use chrono::{DateTime, Utc};
 use rustls::server::{ClientHello, ResolvesServerCert};
 use rustls::sign::CertifiedKey;
 use std::collections::BTreeMap;
+use std::fmt::{self, Debug, Formatter};
 use std::sync::Arc;
 use std::sync::Mutex;
 
-#[derive(Debug)]
+/// Provides a certificate to serve while no certificate obtained via ACME is available.
+///
+/// The provider is asked on every handshake for which no ACME certificate has been deployed yet,
+/// e.g. because the CA is unreachable and the cache is empty. It is free to refresh or rotate the
+/// certificate it returns independently of the ACME state machine. Closures returning
+/// `Option<Arc<CertifiedKey>>` implement this trait.
+pub trait FallbackCertProvider: Send + Sync {
+    fn certificate(&self) -> Option<Arc<CertifiedKey>>;
+}
+
+impl<F: Fn() -> Option<Arc<CertifiedKey>> + Send + Sync> FallbackCertProvider for F {
+    fn certificate(&self) -> Option<Arc<CertifiedKey>> {
+        self()
+    }
+}
+
+/// Certificate resolver serving the deployed certificate, and challenge certificates to
+/// TLS-ALPN-01 validation requests.
+///
+/// Regular handshakes are answered with the deployed certificate whatever their SNI, so a
+/// certificate for `*.example.com` is also served to `api.example.com`. Checking that the SNI is
+/// covered by the certificate's names, including wildcard matching, is left to the client.
+///
+/// Validation requests are recognized by offering only the `acme-tls/1` ALPN protocol, and
+/// answered with the challenge certificate for their SNI. Some validators send no SNI, these get
+/// the most recently created challenge certificate. If challenges for several domains are pending
+/// at the same time, that may be the one for another domain, and the validation fails. Limit
+/// [AcmeConfig::max_concurrent_authorizations](crate::AcmeConfig::max_concurrent_authorizations)
+/// to 1 when using such a CA with several domains.
 pub struct ResolvesServerCertAcme {
     inner: Mutex<Inner>,
+    fallback: Option<Arc<dyn FallbackCertProvider>>,
+}
+
+impl Debug for ResolvesServerCertAcme {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResolvesServerCertAcme")
+            .field("inner", &self.inner)
+            .field("fallback", &self.fallback.is_some())
+            .finish()
+    }
 }
 
 #[derive(Debug)]
 struct Inner {
     cert: Option<Arc<CertifiedKey>>,
     auth_keys: BTreeMap<String, Arc<CertifiedKey>>,
+    /// The most recently set auth key, for validation requests without SNI.
+    last_auth_key: Option<Arc<CertifiedKey>>,
+    challenge_key: Option<Arc<ChallengeKey>>,
+    /// Test certificates of [AcmeState::self_check](crate::AcmeState::self_check), served if no
+    /// auth key is set for their domain.
+    #[cfg(feature = "self-check")]
+    self_check_keys: BTreeMap<String, Arc<CertifiedKey>>,
 }
 
 impl ResolvesServerCertAcme {
-    pub(crate) fn new() -> Arc<Self> {
+    pub(crate) fn new(fallback: Option<Arc<dyn FallbackCertProvider>>) -> Arc<Self> {
         Arc::new(Self {
             inner: Mutex::new(Inner {
                 cert: None,
                 auth_keys: Default::default(),
+                last_auth_key: None,
+                challenge_key: None,
+                #[cfg(feature = "self-check")]
+                self_check_keys: Default::default(),
             }),
+            fallback,
         })
     }
+    /// The currently deployed certificate, if any.
+    pub fn certified_key(&self) -> Option<Arc<CertifiedKey>> {
+        self.inner.lock().unwrap().cert.clone()
+    }
+    /// Not-before and not-after of the currently deployed leaf certificate.
+    pub fn validity(&self) -> Option<[DateTime<Utc>; 2]> {
+        let cert = self.certified_key()?;
+        certificate::validity(cert.end_entity_cert().ok()?).ok()
+    }
+    /// DNS names in the subject alternative names of the currently deployed leaf certificate.
+    pub fn subject_alt_names(&self) -> Option<Vec<String>> {
+        let cert = self.certified_key()?;
+        certificate::dns_names(cert.end_entity_cert().ok()?).ok()
+    }
     pub(crate) fn set_cert(&self, cert: Arc<CertifiedKey>) {
         self.inner.lock().unwrap().cert = Some(cert);
     }
     pub(crate) fn set_auth_key(&self, domain: String, cert: Arc<CertifiedKey>) {
-        self.inner.lock().unwrap().auth_keys.insert(domain, cert);
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_auth_key = Some(cert.clone());
+        inner.auth_keys.insert(domain, cert);
+    }
+    #[cfg(feature = "self-check")]
+    pub(crate) fn set_self_check_key(&self, domain: String, cert: Option<Arc<CertifiedKey>>) {
+        let mut inner = self.inner.lock().unwrap();
+        match cert {
+            Some(cert) => inner.self_check_keys.insert(domain, cert),
+            None => inner.self_check_keys.remove(&domain),
+        };
+    }
+    /// The key signing challenge certificates, generated on first use.
+    pub(crate) fn challenge_key(&self) -> Result<Arc<ChallengeKey>, rcgen::Error> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(key) = &inner.challenge_key {
+            return Ok(key.clone());
+        }
+        let key = Arc::new(ChallengeKey::generate()?);
+        inner.challenge_key = Some(key.clone());
+        Ok(key)
     }
 }
 
@@ -43,17 +131,91 @@ impl ResolvesServerCert for ResolvesServerCertAcme {
         if is_acme_challenge {
             match client_hello.server_name() {
                 None => {
-                    log::debug!("client did not supply SNI");
-                    None
+                    let inner = self.inner.lock().unwrap();
+                    if inner.auth_keys.len() > 1 {
+                        logging::debug!(
+                            "client did not supply SNI, answering with the most recent of {} challenge certs",
+                            inner.auth_keys.len()
+                        );
+                    }
+                    inner.last_auth_key.clone()
                 }
                 Some(domain) => {
                     let domain = domain.to_owned();
                     let domain: String = AsRef::<str>::as_ref(&domain).into();
-                    self.inner.lock().unwrap().auth_keys.get(&domain).cloned()
+                    let inner = self.inner.lock().unwrap();
+                    let auth_key = inner.auth_keys.get(&domain);
+                    #[cfg(feature = "self-check")]
+                    let auth_key = auth_key.or_else(|| inner.self_check_keys.get(&domain));
+                    auth_key.cloned()
                 }
             }
         } else {
-            self.inner.lock().unwrap().cert.clone()
+            let cert = self.inner.lock().unwrap().cert.clone();
+            match (cert, &self.fallback) {
+                (Some(cert), _) => Some(cert),
+                (None, Some(fallback)) => fallback.certificate(),
+                (None, None) => None,
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto;
+    use crate::crypto::rustls_backend::sign::any_ecdsa_type;
+    use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName};
+    use rustls::RootCertStore;
+    use std::convert::TryFrom;
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    fn self_signed() -> Arc<CertifiedKey> {
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let params = rcgen::CertificateParams::new(vec!["example.com".to_string()]).unwrap();
+        let cert = params.self_signed(&key_pair).unwrap();
+        let key = PrivatePkcs8KeyDer::from(key_pair.serialize_der()).into();
+        let key = any_ecdsa_type(&key).unwrap();
+        Arc::new(CertifiedKey::new(vec![cert.der().clone()], key))
+    }
+
+    /// The leaf certificate `resolver` serves to a handshake trusting `trusted`.
+    async fn served_cert(
+        resolver: Arc<ResolvesServerCertAcme>,
+        trusted: &CertifiedKey,
+    ) -> CertificateDer<'static> {
+        let server_config =
+            crypto::server_config_builder(crypto::default_provider()).with_cert_resolver(resolver);
+        let mut roots = RootCertStore::empty();
+        roots.add(trusted.cert[0].clone()).unwrap();
+        let client_config = crypto::client_config_builder(crypto::default_provider())
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let connector = TlsConnector::from(Arc::new(client_config));
+        let server_name = ServerName::try_from("example.com").unwrap();
+        let (_server, client) = tokio::join!(
+            acceptor.accept(server),
+            connector.connect(server_name, client)
+        );
+        let client = client.unwrap();
+        client.get_ref().1.peer_certificates().unwrap()[0].clone()
+    }
+
+    #[tokio::test]
+    async fn fallback_cert_is_served_until_acme_cert_is_deployed() {
+        let fallback = self_signed();
+        let provided = fallback.clone();
+        let resolver = ResolvesServerCertAcme::new(Some(Arc::new(move || Some(provided.clone()))));
+        assert_eq!(
+            served_cert(resolver.clone(), &fallback).await,
+            fallback.cert[0]
+        );
+
+        let acme = self_signed();
+        resolver.set_cert(acme.clone());
+        assert_eq!(served_cert(resolver, &acme).await, acme.cert[0]);
+    }
+}
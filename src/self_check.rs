@@ -0,0 +1,156 @@
+//! Pre-flight check that TLS-ALPN-01 validation requests for the configured domains reach this
+//! process.
+
+use crate::acme::{ChallengeKey, ACME_TLS_ALPN_NAME};
+use crate::crypto::backend::rand::{SecureRandom, SystemRandom};
+use crate::{logging, ResolvesServerCertAcme};
+use rcgen::{CertificateParams, CustomExtension};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+/// Time allowed for connecting to a domain and completing the handshake.
+const SELF_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum SelfCheckError {
+    #[error("could not create the test certificate: {0}")]
+    Rcgen(#[from] rcgen::Error),
+    #[error("validation request to {domain}:443 failed: {error}")]
+    Connect {
+        domain: String,
+        error: std::io::Error,
+    },
+    /// The request reached a server not answering with the certificates of this state, e.g.
+    /// due to wrong DNS records or a proxy terminating TLS.
+    #[error("validation request to {domain}:443 was answered with another certificate")]
+    WrongCertificate { domain: String },
+}
+
+/// Send a TLS-ALPN-01 validation request to port 443 of each of `domains` and check that it is
+/// answered with a test certificate served by `resolver`.
+///
+/// Wildcard domains are skipped, as they cannot be validated with TLS-ALPN-01.
+pub(crate) async fn check(
+    domains: Vec<String>,
+    resolver: Arc<ResolvesServerCertAcme>,
+    provider: Arc<CryptoProvider>,
+) -> Result<(), SelfCheckError> {
+    let mut config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .expect("the provider supports the default protocol versions")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCert(provider)))
+        .with_no_client_auth();
+    config.alpn_protocols = vec![ACME_TLS_ALPN_NAME.to_vec()];
+    let connector = TlsConnector::from(Arc::new(config));
+    let key = ChallengeKey::generate()?;
+    for domain in domains {
+        if domain.starts_with("*.") {
+            logging::info!("skipping self check of wildcard domain {}", domain);
+            continue;
+        }
+        let mut params = CertificateParams::new(vec![domain.clone()])?;
+        let marker: [u8; 32] = rand_marker();
+        params
+            .custom_extensions
+            .push(CustomExtension::new_acme_identifier(&marker));
+        let cert = Arc::new(key.self_signed(params)?);
+        resolver.set_self_check_key(domain.clone(), Some(cert.clone()));
+        let result = served_cert(&connector, &domain).await;
+        resolver.set_self_check_key(domain.clone(), None);
+        let served = match result {
+            Ok(served) => served,
+            Err(error) => return Err(SelfCheckError::Connect { domain, error }),
+        };
+        if served.as_ref() != Some(&cert.cert[0]) {
+            return Err(SelfCheckError::WrongCertificate { domain });
+        }
+        logging::info!("self check of {} succeeded", domain);
+    }
+    Ok(())
+}
+
+/// The leaf certificate served to a validation request to `domain`.
+async fn served_cert(
+    connector: &TlsConnector,
+    domain: &str,
+) -> std::io::Result<Option<CertificateDer<'static>>> {
+    let server_name = ServerName::try_from(domain.to_string())
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let connect = async {
+        let tcp = TcpStream::connect((domain, 443)).await?;
+        connector.connect(server_name, tcp).await
+    };
+    let tls = tokio::time::timeout(SELF_CHECK_TIMEOUT, connect)
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+    let (_, connection) = tls.get_ref();
+    let leaf = connection
+        .peer_certificates()
+        .and_then(|certs| certs.first());
+    Ok(leaf.map(|cert| cert.clone().into_owned()))
+}
+
+/// Random contents of the `acmeIdentifier` extension, telling the test certificate apart from
+/// certificates served by other processes.
+fn rand_marker() -> [u8; 32] {
+    let mut marker = [0; 32];
+    SystemRandom::new()
+        .fill(&mut marker)
+        .expect("the system RNG is available");
+    marker
+}
+
+/// Accepts any server certificate, which is compared with the test certificate afterwards.
+#[derive(Debug)]
+struct AnyCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
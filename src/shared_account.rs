@@ -0,0 +1,62 @@
+use crate::acme::{Account, AcmeError, Directory};
+use crate::crypto::SecretBytes;
+use futures::lock::Mutex as AsyncMutex;
+use reqwest::Client;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// An ACME account shared by several [AcmeState](crate::AcmeState)s.
+///
+/// Pass clones of the same handle to
+/// [AcmeConfig::shared_account](crate::AcmeConfig::shared_account) of each config. The account key
+/// is then generated or loaded from the cache only once, and the account is registered with the CA
+/// by the first order at each directory and reused by all later ones. All configs sharing an
+/// account must use the same primary directory, contact and account cache.
+#[derive(Clone, Default)]
+pub struct SharedAccount {
+    key_pair: Arc<Mutex<Option<SecretBytes>>>,
+    accounts: Arc<AsyncMutex<HashMap<String, Arc<Account>>>>,
+}
+
+impl SharedAccount {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The account key, set to the result of `f` if there is none yet. Returns whether it was set.
+    pub(crate) fn key_pair_or_insert_with(
+        &self,
+        f: impl FnOnce() -> SecretBytes,
+    ) -> (SecretBytes, bool) {
+        let mut key_pair = self.key_pair.lock().unwrap();
+        match &*key_pair {
+            Some(key_pair) => (key_pair.clone(), false),
+            None => {
+                let new = f();
+                *key_pair = Some(new.clone());
+                (new, true)
+            }
+        }
+    }
+    /// The account registered at `directory_url`, registering it with `key_pair` on first use.
+    pub(crate) async fn account(
+        &self,
+        client: &Client,
+        directory_url: &str,
+        contact: &[String],
+        key_pair: &[u8],
+        agree_tos: bool,
+        log_requests: bool,
+    ) -> Result<Arc<Account>, AcmeError> {
+        let mut accounts = self.accounts.lock().await;
+        if let Some(account) = accounts.get(directory_url) {
+            return Ok(account.clone());
+        }
+        let mut directory = Directory::discover(client, directory_url).await?;
+        directory.log_requests = log_requests;
+        let account =
+            Account::create_with_keypair_and_tos(client, directory, contact, key_pair, agree_tos);
+        let new = Arc::new(account.await?);
+        accounts.insert(directory_url.into(), new.clone());
+        Ok(new)
+    }
+}
@@ -1,48 +1,138 @@
+use std::collections::{HashMap, VecDeque};
 use std::convert::Infallible;
 use std::fmt::Debug;
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
 use std::time::Duration;
 
-use chrono::{DateTime, TimeZone, Utc};
-use futures::future::try_join_all;
-use futures::{ready, FutureExt, Stream};
-use rcgen::{CertificateParams, DistinguishedName, Error as RcgenError, PKCS_ECDSA_P256_SHA256};
-use rustls::crypto::ring::sign::any_ecdsa_type;
-use rustls::pki_types::{CertificateDer as RustlsCertificate, PrivateKeyDer, PrivatePkcs8KeyDer};
+use chrono::{DateTime, Utc};
+use futures::stream::FusedStream;
+use futures::{ready, FutureExt, Stream, StreamExt, TryStreamExt};
+use rcgen::{CertificateParams, CustomExtension, Error as RcgenError, PKCS_ECDSA_P256_SHA256};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{
+    CertificateDer as RustlsCertificate, PrivateKeyDer, PrivatePkcs8KeyDer, PrivateSec1KeyDer,
+};
 use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::time::Sleep;
-use x509_parser::parse_x509_certificate;
 
-use crate::acceptor::AcmeAcceptor;
+use crate::acceptor::{AcceptedOrValidation, AcmeAcceptor};
 use crate::acme::{
-    Account, AcmeError, Auth, AuthStatus, Directory, Identifier, Order, OrderStatus,
+    Account, AcmeError, Auth, AuthStatus, ChallengeType, Directory, HttpsRequestError, Identifier,
+    Order, OrderStatus, Problem, RevocationReason, LETS_ENCRYPT_PRODUCTION_DIRECTORY,
 };
-use crate::{AcmeConfig, Incoming, ResolvesServerCertAcme};
+use crate::cache::{decode_cache_entry, encode_cache_entry};
+use crate::certificate;
+use crate::crypto::SecretBytes;
+use crate::logging;
+use crate::{AcmeConfig, CacheFormatError, ChallengeResponse, Incoming, ResolvesServerCertAcme};
 
 type Timer = std::pin::Pin<Box<Sleep>>;
 type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
 
+/// A timer firing after `d`.
+///
+/// All waits of [AcmeState], including the scheduled renewal and the backoff after failed orders,
+/// use this timer. They follow tokio's clock, so tests can fast-forward through them with
+/// `tokio::time::pause` and `tokio::time::advance` (requires tokio's `test-util` feature).
 pub fn after(d: std::time::Duration) -> Timer {
     Box::pin(tokio::time::sleep(d))
 }
 
+/// Run `future`, measuring how long it took to complete.
+async fn timed<T>(future: impl Future<Output = T>) -> (T, Duration) {
+    let start = tokio::time::Instant::now();
+    let output = future.await;
+    (output, start.elapsed())
+}
+
+/// Delay before retrying an order that failed with a transient error.
+const TRANSIENT_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Attempts and delay for downloading an issued certificate, which some CAs briefly serve empty.
+const CERT_DOWNLOAD_ATTEMPTS: usize = 3;
+const CERT_DOWNLOAD_DELAY: Duration = Duration::from_secs(2);
+
+/// Base delay between attempts to store a new certificate or account key in the cache.
+const CACHE_STORE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Remaining lifetime below which a remembered valid authorization is fetched again, so it
+/// cannot expire before the order is finalized.
+const VALID_AUTH_MARGIN: chrono::Duration = chrono::Duration::minutes(10);
+
+/// Delay before the poll `attempt` (starting at 0), doubling with every attempt.
+fn poll_delay(base_delay: Duration, attempt: usize) -> Duration {
+    base_delay.saturating_mul(1 << attempt.min(31))
+}
+
+/// Run the cache store `store`, retrying it up to `retries` times with exponential backoff.
+async fn store_with_retries<F, Fut, E>(what: &str, retries: usize, mut store: F) -> Result<(), E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+    E: Debug,
+{
+    let mut attempt = 0;
+    loop {
+        {
+            let err = match store().await {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            if attempt >= retries {
+                return Err(err);
+            }
+            logging::warn!("storing {} failed, retrying: {:?}", what, err);
+        }
+        after(poll_delay(CACHE_STORE_RETRY_DELAY, attempt)).await;
+        attempt += 1;
+    }
+}
+
+/// TLS Feature extension (RFC 7633) containing only `status_request`, i.e. OCSP Must-Staple.
+fn must_staple_extension() -> CustomExtension {
+    const TLS_FEATURE_OID: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 1, 24];
+    // SEQUENCE { INTEGER 5 }
+    CustomExtension::from_oid_content(TLS_FEATURE_OID, vec![0x30, 0x03, 0x02, 0x01, 0x05])
+}
+
 #[allow(clippy::type_complexity)]
 pub struct AcmeState<EC: Debug = Infallible, EA: Debug = EC> {
     config: Arc<AcmeConfig<EC, EA>>,
     resolver: Arc<ResolvesServerCertAcme>,
-    account_key: Option<Vec<u8>>,
+    account_key: Option<SecretBytes>,
 
     early_action: Option<BoxFuture<Event<EC, EA>>>,
-    load_cert: Option<BoxFuture<Result<Option<Vec<u8>>, EC>>>,
-    load_account: Option<BoxFuture<Result<Option<Vec<u8>>, EA>>>,
-    order: Option<BoxFuture<Result<Vec<u8>, OrderError>>>,
+    load_cert: Option<BoxFuture<(Result<Option<(usize, Vec<u8>)>, EC>, Duration)>>,
+    /// Cert cache entry loaded at startup, deployed after [EventOk::CertCacheLoad] is produced.
+    loaded_cert: Option<(usize, SecretBytes)>,
+    load_account: Option<BoxFuture<(Result<Option<Vec<u8>>, EA>, Duration)>>,
+    order: Option<BoxFuture<Result<(SecretBytes, String), OrderError>>>,
+    revocation: Option<BoxFuture<Event<EC, EA>>>,
+    /// Overwriting the cached certificate, see [AcmeState::rotate_on_compromise].
+    discard_cached_cert: Option<BoxFuture<Event<EC, EA>>>,
+    discard_cert_key: bool,
+    progress: Arc<OrderProgress>,
+    certificate_url: Option<String>,
+    /// The account URL last reported with [EventOk::AccountUrl].
+    reported_account_url: Option<String>,
+    /// Key and chain of the deployed certificate.
+    pem: Option<SecretBytes>,
     backoff_cnt: usize,
+    /// Consecutive failed orders, see [AcmeConfig::max_order_attempts].
+    failed_orders: usize,
+    directory: usize,
+    failovers: usize,
     wait: Option<Timer>,
+    config_error: Option<ConfigError>,
+    terminated: bool,
+    #[cfg(feature = "tokio-util")]
+    cancelled: Option<Pin<Box<tokio_util::sync::WaitForCancellationFutureOwned>>>,
 }
 
 pub type Event<EC, EA> = Result<EventOk, EventError<EC, EA>>;
@@ -50,9 +140,105 @@ pub type Event<EC, EA> = Result<EventOk, EventError<EC, EA>>;
 #[derive(Debug)]
 pub enum EventOk {
     DeployedCachedCert,
-    DeployedNewCert,
+    DeployedNewCert {
+        certificate_url: String,
+    },
     CertCacheStore,
     AccountCacheStore,
+    /// The cert cache was queried at startup, taking `elapsed`.
+    CertCacheLoad {
+        hit: bool,
+        elapsed: Duration,
+    },
+    /// The account cache was queried at startup, taking `elapsed`.
+    AccountCacheLoad {
+        hit: bool,
+        elapsed: Duration,
+    },
+    /// The previously deployed certificate was revoked, see [AcmeState::rotate_on_compromise].
+    Revoked,
+    /// The cached certificate was discarded, see [AcmeState::rotate_on_compromise].
+    CachedCertDiscarded,
+    /// An order uses the account at `url`, which differs from the one reported before, e.g.
+    /// after the account was created or a fallback directory was used.
+    AccountUrl {
+        url: String,
+    },
+}
+
+/// Whether a valid certificate is deployed, see [AcmeState::readiness].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadinessStatus {
+    /// A certificate valid until `not_after` is deployed.
+    Ready { not_after: DateTime<Utc> },
+    /// No certificate has been deployed yet.
+    NoCertificate,
+    /// The deployed certificate is not valid before `not_before`.
+    NotYetValid { not_before: DateTime<Utc> },
+    /// The deployed certificate expired at `not_after`.
+    Expired { not_after: DateTime<Utc> },
+}
+
+/// The retry after failed orders, see [AcmeState::backoff_status].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackoffStatus {
+    /// The number of the next order attempt, counting the consecutive failed ones before it.
+    pub attempt: usize,
+    /// When the next order is started.
+    pub next_retry: DateTime<Utc>,
+}
+
+/// Progress of the order in flight, shared with the order future.
+struct OrderProgress {
+    phase: Mutex<AcmePhase>,
+    /// Triggered challenges, with the URL of their authorization.
+    challenges: Mutex<Vec<(String, ChallengeResponse)>>,
+    /// Authorizations seen valid by earlier orders, with their domain and expiry, by URL.
+    valid_auths: Mutex<HashMap<String, (String, DateTime<Utc>)>>,
+    request_counts: Mutex<RequestCounts>,
+    /// URL of the account used by the latest order.
+    account_url: Mutex<Option<String>>,
+    /// Times of the finalize requests within the window of [AcmeConfig::max_issuances_per].
+    finalized_at: Mutex<VecDeque<DateTime<Utc>>>,
+}
+
+impl OrderProgress {
+    fn new() -> Self {
+        Self {
+            phase: Mutex::new(AcmePhase::Ordering),
+            challenges: Mutex::new(Vec::new()),
+            valid_auths: Mutex::new(HashMap::new()),
+            request_counts: Mutex::new(RequestCounts::default()),
+            account_url: Mutex::new(None),
+            finalized_at: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+/// Rate limited requests sent to the CA by an [AcmeState], see [AcmeState::request_counts].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RequestCounts {
+    /// newOrder requests, which count towards the new orders limit.
+    pub new_orders: u64,
+    /// finalize requests, each of which issues a certificate if it succeeds.
+    pub finalizations: u64,
+}
+
+/// The step the state machine is currently busy with, see [AcmeState::phase].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AcmePhase {
+    /// Loading the certificate or the account key from the cache.
+    LoadingCache,
+    /// Creating or polling an order.
+    Ordering,
+    /// Completing the TLS-ALPN-01 challenge for a domain.
+    Authorizing(String),
+    /// Sending the CSR and downloading the certificate.
+    Finalizing,
+    /// Waiting for the next renewal, or for the backoff after an error.
+    Idle { until: DateTime<Utc> },
+    /// The stream has ended.
+    Terminated,
 }
 
 #[derive(Error, Debug)]
@@ -67,10 +253,46 @@ pub enum EventError<EC: Debug, EA: Debug> {
     AccountCacheStore(EA),
     #[error("cached cert parse: {0}")]
     CachedCertParse(CertParseError),
+    /// The cached cert does not cover all configured domains, so a new one is ordered instead.
+    #[error("cached cert does not cover {missing:?}")]
+    CachedCertDomainMismatch { missing: Vec<String> },
+    #[error("cached account parse: {0}")]
+    CachedAccountParse(CacheFormatError),
     #[error("order: {0}")]
     Order(OrderError),
+    /// The last order failed and [AcmeConfig::max_order_attempts] is reached, the stream ends.
+    #[error("giving up after {attempts} failed orders, last: {last}")]
+    OrdersExhausted { attempts: usize, last: OrderError },
+    /// [AcmeConfig::max_issuances_per] is reached, the next order is started at `retry_at`.
+    #[error("{count} certificates were ordered within {window:?}, waiting until {retry_at}")]
+    IssuanceCapReached {
+        count: usize,
+        window: Duration,
+        retry_at: DateTime<Utc>,
+    },
     #[error("new cert parse: {0}")]
     NewCertParse(CertParseError),
+    #[error("invalid config: {0}")]
+    Config(ConfigError),
+    #[error("revocation: {0}")]
+    Revoke(AcmeError),
+}
+
+/// Error of [AcmeState::bootstrap] and [AcmeState::obtain_certificate].
+#[derive(Error, Debug)]
+pub enum ObtainError<EC: Debug, EA: Debug> {
+    #[error(transparent)]
+    Event(#[from] EventError<EC, EA>),
+    #[error("the state terminated before a certificate was deployed")]
+    Terminated,
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("the production directory requires a cache, see AcmeConfig::allow_no_cache")]
+    NoCacheInProduction,
+    #[error("no directory was chosen, see AcmeConfig::require_explicit_directory")]
+    NoExplicitDirectory,
 }
 
 #[derive(Error, Debug)]
@@ -81,12 +303,96 @@ pub enum OrderError {
     Rcgen(#[from] RcgenError),
     #[error("bad order object: {0:?}")]
     BadOrder(Order),
-    #[error("bad auth object: {0:?}")]
-    BadAuth(Auth),
+    #[error("authorization for {domain} failed: {auth:?}")]
+    BadAuth { domain: String, auth: Auth },
     #[error("authorization for {0} failed too many times")]
     TooManyAttemptsAuth(String),
     #[error("order status stayed on processing too long")]
     ProcessingTimeout(Order),
+    #[error("order did not complete within {0:?}")]
+    DeadlineExceeded(Duration),
+    #[error("CAA records of {domain} forbid issuance by this CA: {records:?}")]
+    CaaForbidden {
+        domain: String,
+        records: Vec<String>,
+    },
+    #[error("finalizing the order failed: {0:?}")]
+    FinalizeFailed(Problem),
+    #[error("downloaded certificate is invalid: {0}")]
+    BadCertificate(CertParseError),
+    #[error("pre-deploy hook failed: {0}")]
+    PreDeployHook(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl OrderError {
+    /// Whether the order failed due to a network problem or a server error, which is likely to be
+    /// resolved by retrying soon.
+    ///
+    /// Transient errors are retried after a short fixed delay, all others with an exponential
+    /// backoff.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            OrderError::Acme(AcmeError::Io(_)) => true,
+            OrderError::Acme(AcmeError::HttpRequest(err)) => matches!(
+                err,
+                HttpsRequestError::Io(_)
+                    | HttpsRequestError::Http(_)
+                    | HttpsRequestError::Non2xxStatus {
+                        status_code: 500..=599,
+                        ..
+                    }
+            ),
+            _ => false,
+        }
+    }
+    /// The delay requested by the CA when it rate limited the order.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            OrderError::Acme(AcmeError::HttpRequest(HttpsRequestError::RateLimited {
+                retry_after,
+                ..
+            })) => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+/// Whether `pem` is a `PRIVATE KEY` (PKCS#8) or `EC PRIVATE KEY` (SEC1).
+///
+/// Locating the key by its label instead of its position also accepts entries written by other
+/// tools, besides the key-first layout written by this crate.
+fn is_private_key(pem: &pem::Pem) -> bool {
+    matches!(pem.tag(), "PRIVATE KEY" | "EC PRIVATE KEY")
+}
+
+/// The private key in `pem`, taking its contents without a copy.
+fn private_key(pem: pem::Pem) -> Option<PrivateKeyDer<'static>> {
+    match pem.tag() {
+        "PRIVATE KEY" => Some(PrivatePkcs8KeyDer::from(pem.into_contents()).into()),
+        "EC PRIVATE KEY" => Some(PrivateSec1KeyDer::from(pem.into_contents()).into()),
+        _ => None,
+    }
+}
+
+/// Drop `pems`, zeroing their contents like [SecretBytes].
+fn discard_pems(pems: impl IntoIterator<Item = pem::Pem>) {
+    for pem in pems {
+        drop(SecretBytes::from(pem.into_contents()));
+    }
+}
+
+/// Extract the problem document from a finalize request rejected by the CA.
+fn finalize_error(err: AcmeError) -> OrderError {
+    if let AcmeError::HttpRequest(HttpsRequestError::Non2xxStatus {
+        status_code: 400..=499,
+        body,
+    }) = &err
+    {
+        if let Ok(problem @ Problem { typ: Some(_), .. }) = serde_json::from_str(body) {
+            return OrderError::FinalizeFailed(problem);
+        }
+    }
+    err.into()
 }
 
 #[derive(Error, Debug)]
@@ -99,6 +405,12 @@ pub enum CertParseError {
     TooFewPem(usize),
     #[error("unsupported private key type")]
     InvalidPrivateKey,
+    #[error("no PRIVATE KEY or EC PRIVATE KEY pem")]
+    MissingPrivateKey,
+    #[error("no CERTIFICATE pem")]
+    MissingCertificate,
+    #[error("cache format: {0}")]
+    CacheFormat(#[from] CacheFormatError),
 }
 
 impl<EC: 'static + Debug, EA: 'static + Debug> AcmeState<EC, EA> {
@@ -114,10 +426,37 @@ impl<EC: 'static + Debug, EA: 'static + Debug> AcmeState<EC, EA> {
         let acceptor = self.acceptor();
         Incoming::new(tcp_incoming, self, acceptor, alpn_protocols)
     }
+    /// Turn a stream of TCP connections into a stream of TLS connections served with a custom
+    /// `server_config`.
+    ///
+    /// See [Incoming::with_server_config] for how `server_config` and `alpn_protocols` are merged.
+    pub fn incoming_with_server<
+        TCP: AsyncRead + AsyncWrite + Unpin,
+        ETCP,
+        ITCP: Stream<Item = Result<TCP, ETCP>> + Unpin,
+    >(
+        self,
+        tcp_incoming: ITCP,
+        server_config: ServerConfig,
+        alpn_protocols: Vec<Vec<u8>>,
+    ) -> Incoming<TCP, ETCP, ITCP, EC, EA> {
+        let acceptor = self.acceptor();
+        Incoming::with_server_config(tcp_incoming, self, acceptor, server_config, alpn_protocols)
+    }
+    /// Create an acceptor answering validation requests for this state.
+    ///
+    /// The challenge keys are held by the shared [ResolvesServerCertAcme], not by the acceptor, so
+    /// any number of acceptors can be created and each of them answers validation requests. This
+    /// allows e.g. a dedicated listener on port 443, where the CA connects to, while regular
+    /// traffic is accepted on another port.
     pub fn acceptor(&self) -> AcmeAcceptor {
-        AcmeAcceptor::new(self.resolver())
+        AcmeAcceptor::with_crypto_provider(self.resolver(), self.crypto_provider())
     }
 
+    /// Create an acceptor for [axum_server] that finishes regular handshakes with `rustls_config`.
+    ///
+    /// TLS-ALPN-01 validation requests are answered internally, so `rustls_config` does not need
+    /// to advertise the `acme-tls/1` protocol.
     #[cfg(feature = "axum")]
     pub fn axum_acceptor(
         &self,
@@ -128,65 +467,343 @@ impl<EC: 'static + Debug, EA: 'static + Debug> AcmeState<EC, EA> {
     pub fn resolver(&self) -> Arc<ResolvesServerCertAcme> {
         self.resolver.clone()
     }
+    /// Check that TLS-ALPN-01 validation requests for the configured domains reach the acceptors
+    /// of this state, before the CA sends them.
+    ///
+    /// For each domain, a test certificate is set in the [ResolvesServerCertAcme] and a
+    /// validation request is sent to port 443 of the domain, which must be answered with it.
+    /// This catches firewalls, wrong DNS records and proxies terminating TLS. Wildcard domains
+    /// are skipped. The returned future does not borrow the state, so it can be awaited after
+    /// the state was handed to [AcmeState::incoming] or another listener answering on port 443.
+    #[cfg(feature = "self-check")]
+    pub fn self_check(
+        &self,
+    ) -> impl Future<Output = Result<(), crate::SelfCheckError>> + Send + 'static {
+        let domains = self.config.domains.clone();
+        crate::self_check::check(domains, self.resolver(), self.crypto_provider())
+    }
+    /// The provider set with [AcmeConfig::crypto_provider].
+    pub(crate) fn crypto_provider(&self) -> Arc<CryptoProvider> {
+        self.config.crypto_provider.clone()
+    }
+    /// The TLS versions set with [AcmeConfig::tls_versions].
+    pub(crate) fn tls_versions(&self) -> Option<&[&'static rustls::SupportedProtocolVersion]> {
+        self.config.tls_versions.as_deref()
+    }
     pub fn new(config: AcmeConfig<EC, EA>) -> Self {
+        let config_error = if config.require_explicit_directory && !config.has_explicit_directory {
+            Some(ConfigError::NoExplicitDirectory)
+        } else if config
+            .directory_urls()
+            .any(|url| url == LETS_ENCRYPT_PRODUCTION_DIRECTORY)
+            && !config.cache.is_durable()
+            && !config.allow_no_cache
+        {
+            Some(ConfigError::NoCacheInProduction)
+        } else {
+            None
+        };
         let config = Arc::new(config);
         Self {
             config: config.clone(),
-            resolver: ResolvesServerCertAcme::new(),
-            account_key: None,
+            resolver: ResolvesServerCertAcme::new(config.fallback_cert_provider.clone()),
+            account_key: config.account_key.clone(),
             early_action: None,
             load_cert: Some(Box::pin({
                 let config = config.clone();
-                async move {
-                    config
-                        .cache
-                        .load_cert(&config.domains, &config.directory_url)
-                        .await
-                }
-            })),
-            load_account: Some(Box::pin({
-                let config = config;
-                async move {
-                    config
-                        .cache
-                        .load_account(&config.contact, &config.directory_url)
-                        .await
-                }
+                let load = async move {
+                    for (index, directory_url) in config.directory_urls().enumerate() {
+                        let cert = config
+                            .cache
+                            .load_cert(&config.domains, directory_url)
+                            .await?;
+                        if let Some(cert) = cert.filter(|cert| !cert.is_empty()) {
+                            return Ok(Some((index, cert)));
+                        }
+                    }
+                    Ok(None)
+                };
+                timed(load)
             })),
+            loaded_cert: None,
+            load_account: match config.account_key {
+                Some(_) => None,
+                None => Some(Box::pin({
+                    let config = config.clone();
+                    timed(async move {
+                        config
+                            .cache
+                            .load_account(&config.contact, &config.directory_url)
+                            .await
+                    })
+                })),
+            },
             order: None,
+            revocation: None,
+            discard_cached_cert: None,
+            discard_cert_key: false,
+            progress: Arc::new(OrderProgress::new()),
+            certificate_url: None,
+            reported_account_url: None,
+            pem: None,
             backoff_cnt: 0,
+            failed_orders: 0,
+            directory: 0,
+            failovers: 0,
             wait: None,
+            config_error,
+            terminated: false,
+            #[cfg(feature = "tokio-util")]
+            cancelled: config
+                .cancellation_token
+                .clone()
+                .map(|token| Box::pin(token.cancelled_owned())),
         }
     }
-    fn parse_cert(pem: &[u8]) -> Result<(CertifiedKey, [DateTime<Utc>; 2]), CertParseError> {
-        let mut pems = pem::parse_many(pem)?;
+    /// Order a new certificate on the next poll, regardless of the validity of the current one.
+    ///
+    /// Cancels the wait for the scheduled renewal (or for an error backoff) and skips loading a
+    /// certificate from the cache, if that has not happened yet. Calling this while an order is
+    /// already in flight has no effect, so repeated calls never start more than one order.
+    pub fn force_renew(&mut self) {
+        if self.order.is_some() {
+            return;
+        }
+        self.wait = None;
+        self.load_cert = None;
+        self.loaded_cert = None;
+    }
+    /// Revoke the deployed certificate because its key was compromised, and order a new one with
+    /// a newly generated key.
+    ///
+    /// The cached certificate and its key are discarded first, so a restart before the new
+    /// certificate is issued does not deploy the revoked one again. This produces
+    /// [EventOk::CachedCertDiscarded] or [EventError::CertCacheStore]. Then [EventOk::Revoked] or
+    /// [EventError::Revoke] follows, and then the events of the new order. Neither a failed
+    /// revocation, e.g. of an expired certificate, nor a failure to discard the cached
+    /// certificate prevents the new order. An order in flight is cancelled. The key set with
+    /// [AcmeConfig::cert_key](crate::AcmeConfig::cert_key) is no longer used by this state, but
+    /// should be replaced in the configuration too.
+    pub fn rotate_on_compromise(&mut self) {
+        let config = self.config.clone();
+        self.discard_cached_cert = Some(Box::pin(async move {
+            for directory_url in config.directory_urls() {
+                let store = || config.cache.remove_cert(&config.domains, directory_url);
+                let retries = config.cache_store_retries;
+                if let Err(err) = store_with_retries("certificate", retries, store).await {
+                    return Err(EventError::CertCacheStore(err));
+                }
+            }
+            Ok(EventOk::CachedCertDiscarded)
+        }));
+        let certificate = self
+            .resolver
+            .certified_key()
+            .and_then(|cert| cert.end_entity_cert().ok().map(|der| der.to_vec()));
+        match (certificate, self.account_key.clone()) {
+            (Some(certificate), Some(key_pair)) => {
+                let config = self.config.clone();
+                let directory_url = self.directory_url().to_string();
+                self.revocation = Some(Box::pin(async move {
+                    let revoke = async {
+                        let client = config.client()?;
+                        let account =
+                            Self::account(&config, &client, &directory_url, &key_pair).await?;
+                        account
+                            .revoke_certificate(
+                                &client,
+                                &certificate,
+                                RevocationReason::KeyCompromise,
+                            )
+                            .await
+                    };
+                    match revoke.await {
+                        Ok(()) => Ok(EventOk::Revoked),
+                        Err(err) => Err(EventError::Revoke(err)),
+                    }
+                }));
+            }
+            _ => logging::warn!("no deployed certificate to revoke"),
+        }
+        self.discard_cert_key = true;
+        self.order = None;
+        self.progress.challenges.lock().unwrap().clear();
+        self.force_renew();
+    }
+    /// The URL of the most recently issued certificate at the CA.
+    ///
+    /// Returns `None` until a certificate has been issued by this state, in particular while a
+    /// certificate loaded from the cache is deployed.
+    pub fn certificate_url(&self) -> Option<&str> {
+        self.certificate_url.as_deref()
+    }
+    /// The PEM encoded private key and certificate chain of the deployed certificate, as issued
+    /// or loaded from the cache, e.g. to hand it to other servers or a secrets manager.
+    ///
+    /// This contains the private key of the certificate. The copy is not zeroed on drop, even with
+    /// the `zeroize` feature, and must be handled with the same care as the cache. It is not part
+    /// of any event, so it does not end up in logs of events.
+    pub fn certificate_pem(&self) -> Option<Vec<u8>> {
+        self.pem.as_ref().map(|pem| pem.to_vec())
+    }
+    /// The URL (`kid`) of the account used by the latest order, once it is registered or found.
+    ///
+    /// Also produced as [EventOk::AccountUrl] whenever it changes.
+    pub fn account_url(&self) -> Option<String> {
+        self.progress.account_url.lock().unwrap().clone()
+    }
+    /// The newOrder and finalize requests sent by this state so far.
+    ///
+    /// The counts are kept in memory only and cover neither other states nor earlier runs.
+    pub fn request_counts(&self) -> RequestCounts {
+        *self.progress.request_counts.lock().unwrap()
+    }
+    /// When the next order may start without exceeding [AcmeConfig::max_issuances_per], if that
+    /// is in the future.
+    fn issuance_cap_reached(&self) -> Option<DateTime<Utc>> {
+        let (window, count) = self.config.max_issuances?;
+        let window = chrono::Duration::from_std(window).ok()?;
+        let now = self.config.clock.now();
+        let mut finalized_at = self.progress.finalized_at.lock().unwrap();
+        while finalized_at.front().is_some_and(|&at| at + window <= now) {
+            finalized_at.pop_front();
+        }
+        match finalized_at.len() >= count {
+            true => Some(finalized_at[finalized_at.len() - count] + window),
+            false => None,
+        }
+    }
+    /// The directory used for the next or current order.
+    fn directory_url(&self) -> &str {
+        self.config.directory_urls().nth(self.directory).unwrap()
+    }
+    /// The step the state machine is currently busy with.
+    ///
+    /// When several domains are authorized concurrently, the most recently started one is
+    /// reported.
+    pub fn phase(&self) -> AcmePhase {
+        if self.terminated {
+            return AcmePhase::Terminated;
+        }
+        if self.load_cert.is_some() || self.loaded_cert.is_some() || self.load_account.is_some() {
+            return AcmePhase::LoadingCache;
+        }
+        if self.order.is_some() {
+            return self.progress.phase.lock().unwrap().clone();
+        }
+        match self.wait_until() {
+            Some(until) => AcmePhase::Idle { until },
+            None => AcmePhase::Ordering,
+        }
+    }
+    /// The retry after failed orders, if the state machine is waiting for it.
+    ///
+    /// `None` while an order is in flight, or while waiting for the next renewal after a
+    /// successful one.
+    pub fn backoff_status(&self) -> Option<BackoffStatus> {
+        if self.terminated || self.order.is_some() || self.failed_orders == 0 {
+            return None;
+        }
+        Some(BackoffStatus {
+            attempt: self.failed_orders + 1,
+            next_retry: self.wait_until()?,
+        })
+    }
+    /// When the current wait ends.
+    fn wait_until(&self) -> Option<DateTime<Utc>> {
+        let remaining = self.wait.as_ref()?.deadline() - tokio::time::Instant::now();
+        let until = chrono::Duration::from_std(remaining)
+            .ok()
+            .and_then(|remaining| self.config.clock.now().checked_add_signed(remaining))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+        Some(until)
+    }
+    /// Whether a currently valid certificate is deployed, e.g. for readiness probes.
+    ///
+    /// Certificates served by a [FallbackCertProvider](crate::FallbackCertProvider) are not
+    /// taken into account.
+    pub fn readiness(&self) -> ReadinessStatus {
+        let [not_before, not_after] = match self.resolver.validity() {
+            Some(validity) => validity,
+            None => return ReadinessStatus::NoCertificate,
+        };
+        let now = self.config.clock.now();
+        if now < not_before {
+            ReadinessStatus::NotYetValid { not_before }
+        } else if now >= not_after {
+            ReadinessStatus::Expired { not_after }
+        } else {
+            ReadinessStatus::Ready { not_after }
+        }
+    }
+    /// Shorthand for [AcmeState::readiness] being [ReadinessStatus::Ready].
+    pub fn is_ready(&self) -> bool {
+        matches!(self.readiness(), ReadinessStatus::Ready { .. })
+    }
+    /// The challenges of the order in flight that have been computed but are not completed yet.
+    ///
+    /// Each is listed before the CA is asked to validate it, and until its authorization is
+    /// valid or has failed.
+    pub fn pending_challenges(&self) -> Vec<ChallengeResponse> {
+        let challenges = self.progress.challenges.lock().unwrap();
+        challenges.iter().map(|(_, c)| c.clone()).collect()
+    }
+    fn parse_cert(
+        provider: &CryptoProvider,
+        pem: &[u8],
+    ) -> Result<(CertifiedKey, [DateTime<Utc>; 2]), CertParseError> {
+        let pems = pem::parse_many(pem)?;
         if pems.len() < 2 {
-            return Err(CertParseError::TooFewPem(pems.len()));
+            let len = pems.len();
+            discard_pems(pems);
+            return Err(CertParseError::TooFewPem(len));
         }
-        let pk_bytes = pems.remove(0).into_contents();
-        let pk_der: PrivatePkcs8KeyDer = pk_bytes.into();
-        let pk: PrivateKeyDer = pk_der.into();
-        let pk = match any_ecdsa_type(&pk) {
+        let mut pk: Option<PrivateKeyDer> = None;
+        let mut cert_chain: Vec<RustlsCertificate> = Vec::new();
+        for p in pems {
+            if p.tag() == "CERTIFICATE" {
+                cert_chain.push(p.into_contents().into());
+            } else if pk.is_none() && is_private_key(&p) {
+                pk = private_key(p);
+            } else {
+                discard_pems([p]);
+            }
+        }
+        let pk = match pk {
+            Some(pk) => pk,
+            None => return Err(CertParseError::MissingPrivateKey),
+        };
+        let pk = match provider.key_provider.load_private_key(pk) {
             Ok(pk) => pk,
             Err(_) => return Err(CertParseError::InvalidPrivateKey),
         };
-        let cert_chain: Vec<RustlsCertificate> =
-            pems.into_iter().map(|p| p.into_contents().into()).collect();
-        let validity = match parse_x509_certificate(cert_chain[0].as_ref()) {
-            Ok((_, cert)) => {
-                let validity = cert.validity();
-                [validity.not_before, validity.not_after]
-                    .map(|t| Utc.timestamp_opt(t.timestamp(), 0).earliest().unwrap())
-            }
-            Err(err) => return Err(CertParseError::X509(err)),
-        };
+        if cert_chain.is_empty() {
+            return Err(CertParseError::MissingCertificate);
+        }
+        let validity = certificate::validity(cert_chain[0].as_ref())?;
         let cert = CertifiedKey::new(cert_chain, pk);
         Ok((cert, validity))
     }
-
+    /// Whether the private key in `pem` is `key`.
+    fn has_key(pem: &[u8], key: &[u8]) -> bool {
+        match pem::parse_many(pem) {
+            Ok(pems) => {
+                let has_key = pems
+                    .iter()
+                    .any(|p| is_private_key(p) && p.contents() == key);
+                discard_pems(pems);
+                has_key
+            }
+            Err(_) => false,
+        }
+    }
+    /// Deploy a certificate, which was either loaded from the cache or, if `certificate_url` is
+    /// set, newly issued.
     #[allow(clippy::result_large_err)]
-    fn process_cert(&mut self, pem: Vec<u8>, cached: bool) -> Event<EC, EA> {
-        let (cert, validity) = match (Self::parse_cert(&pem), cached) {
+    fn process_cert(&mut self, pem: SecretBytes, certificate_url: Option<String>) -> Event<EC, EA> {
+        let cached = certificate_url.is_none();
+        let (cert, validity) = match (Self::parse_cert(&self.config.crypto_provider, &pem), cached)
+        {
             (Ok(r), _) => r,
             (Err(err), cached) => {
                 return match cached {
@@ -195,134 +812,738 @@ impl<EC: 'static + Debug, EA: 'static + Debug> AcmeState<EC, EA> {
                 }
             }
         };
-        self.resolver.set_cert(Arc::new(cert));
-        let wait_duration = (validity[1] - (validity[1] - validity[0]) / 3 - Utc::now())
-            .max(chrono::Duration::zero())
-            .to_std()
-            .unwrap_or_default();
-        self.wait = Some(after(wait_duration));
         if cached {
-            return Ok(EventOk::DeployedCachedCert);
+            let names = cert
+                .end_entity_cert()
+                .ok()
+                .and_then(|leaf| certificate::dns_names(leaf).ok())
+                .unwrap_or_default();
+            let missing: Vec<String> = self
+                .config
+                .domains
+                .iter()
+                .filter(|domain| !names.iter().any(|name| name.eq_ignore_ascii_case(domain)))
+                .cloned()
+                .collect();
+            if !missing.is_empty() {
+                return Err(EventError::CachedCertDomainMismatch { missing });
+            }
+        }
+        let cert = Arc::new(cert);
+        self.resolver.set_cert(cert.clone());
+        self.pem = Some(pem.clone());
+        self.config.metrics.cert_deployed(validity[1]);
+        self.certificate_url = certificate_url.clone();
+        let mut wait_duration =
+            (validity[1] - (validity[1] - validity[0]) / 3 - self.config.clock.now())
+                .max(chrono::Duration::zero())
+                .to_std()
+                .unwrap_or_default();
+        if let Some(cert_key) = &self.config.cert_key {
+            if cached && !Self::has_key(&pem, cert_key) {
+                logging::info!("cached certificate does not use the configured key, renewing");
+                wait_duration = Duration::ZERO;
+            }
         }
+        self.wait = Some(after(wait_duration));
+        let certificate_url = match certificate_url {
+            Some(certificate_url) => certificate_url,
+            None => return Ok(EventOk::DeployedCachedCert),
+        };
         let config = self.config.clone();
+        let directory_url = self.directory_url().to_string();
         self.early_action = Some(Box::pin(async move {
-            match config
-                .cache
-                .store_cert(&config.domains, &config.directory_url, &pem)
-                .await
-            {
+            if let Some(hook) = &config.post_deploy_hook {
+                hook.post_deploy(&cert, &pem).await;
+            }
+            let entry = SecretBytes::from(encode_cache_entry(&pem));
+            let store = || {
+                config
+                    .cache
+                    .store_cert(&config.domains, &directory_url, &entry)
+            };
+            match store_with_retries("certificate", config.cache_store_retries, store).await {
                 Ok(()) => Ok(EventOk::CertCacheStore),
                 Err(err) => Err(EventError::CertCacheStore(err)),
             }
         }));
-        Event::Ok(EventOk::DeployedNewCert)
+        Event::Ok(EventOk::DeployedNewCert { certificate_url })
     }
     async fn order(
         config: Arc<AcmeConfig<EC, EA>>,
         resolver: Arc<ResolvesServerCertAcme>,
-        key_pair: Vec<u8>,
-    ) -> Result<Vec<u8>, OrderError> {
-        let directory = Directory::discover(&config.client_config, &config.directory_url).await?;
-        let account = Account::create_with_keypair(
-            &config.client_config,
-            directory,
-            &config.contact,
-            &key_pair,
-        )
-        .await?;
+        directory_url: String,
+        key_pair: SecretBytes,
+        cert_key: Option<SecretBytes>,
+        progress: Arc<OrderProgress>,
+    ) -> Result<(SecretBytes, String), OrderError> {
+        let set_phase = |p| *progress.phase.lock().unwrap() = p;
+        set_phase(AcmePhase::Ordering);
+        let client = config.client().map_err(AcmeError::from)?;
+        let account = Self::account(&config, &client, &directory_url, &key_pair).await?;
+        *progress.account_url.lock().unwrap() = Some(account.kid.clone());
+        #[cfg(feature = "caa")]
+        if config.check_caa {
+            let meta = account.directory.meta.as_ref();
+            match meta.and_then(|meta| meta.caa_identities.as_ref()) {
+                Some(caa_identities) => crate::caa::check(&config.domains, caa_identities).await?,
+                None => logging::info!("skipping CAA check, directory has no caaIdentities"),
+            }
+        }
 
         let mut params = CertificateParams::new(config.domains.clone())?;
-        params.distinguished_name = DistinguishedName::new();
-        let key_pair = rcgen::KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)?;
+        params.distinguished_name = config.distinguished_name.clone();
+        params.key_usages = config.key_usages.clone();
+        params.extended_key_usages = config.extended_key_usages.clone();
+        if config.must_staple {
+            params.custom_extensions.push(must_staple_extension());
+        }
+        let key_pair = match &cert_key {
+            Some(cert_key) => rcgen::KeyPair::from_pkcs8_der_and_sign_algo(
+                &PrivatePkcs8KeyDer::from(&cert_key[..]),
+                &PKCS_ECDSA_P256_SHA256,
+            )?,
+            None => rcgen::KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)?,
+        };
+        #[cfg(feature = "zeroize")]
+        let key_pair = zeroize::Zeroizing::new(key_pair);
+        let key_pair: &rcgen::KeyPair = &key_pair;
 
-        let (order_url, mut order) = account
-            .new_order(&config.client_config, config.domains.clone())
-            .await?;
+        let (not_before, not_after) = match config.requested_validity {
+            Some(valid_for) => {
+                let now = config.clock.now();
+                let not_after = chrono::Duration::from_std(valid_for)
+                    .ok()
+                    .and_then(|valid_for| now.checked_add_signed(valid_for));
+                (Some(now), not_after)
+            }
+            None => (None, None),
+        };
+        let resumed = match config.resume_orders {
+            true => Self::resume_order(&config, &client, &account, &directory_url).await,
+            false => None,
+        };
+        let (order_url, order) = match resumed {
+            Some(resumed) => resumed,
+            None => {
+                progress.request_counts.lock().unwrap().new_orders += 1;
+                let (order_url, order) = account
+                    .new_order_with_validity(&client, config.domains.clone(), not_before, not_after)
+                    .await?;
+                if config.resume_orders {
+                    Self::store_order_url(&config, &directory_url, Some(&order_url)).await;
+                }
+                (order_url, order)
+            }
+        };
+        let result = Self::complete_order(
+            &config, &client, &resolver, &account, key_pair, &params, &progress, &order_url, order,
+        )
+        .await;
+        let resumable = match &result {
+            Ok(_) => false,
+            Err(err) => err.is_transient() || matches!(err, OrderError::TooManyAttemptsAuth(_)),
+        };
+        if config.resume_orders && !resumable {
+            Self::store_order_url(&config, &directory_url, None).await;
+        }
+        result
+    }
+    /// The stored order for `directory_url`, if it can be resumed.
+    async fn resume_order(
+        config: &AcmeConfig<EC, EA>,
+        client: &reqwest::Client,
+        account: &Account,
+        directory_url: &str,
+    ) -> Option<(String, Order)> {
+        let order_url = match config
+            .cache
+            .load_order(&config.domains, directory_url)
+            .await
+        {
+            Ok(order_url) => order_url?,
+            Err(err) => {
+                logging::warn!("could not load stored order: {:?}", err);
+                return None;
+            }
+        };
+        match account.order(client, &order_url).await {
+            Ok(
+                order @ Order {
+                    status: OrderStatus::Pending | OrderStatus::Ready,
+                    ..
+                },
+            ) if order
+                .expires
+                .is_none_or(|expires| expires > config.clock.now()) =>
+            {
+                logging::info!("resuming order {}", order_url);
+                Some((order_url, order))
+            }
+            Ok(order) => {
+                logging::info!("discarding stored order in state {:?}", order.status);
+                None
+            }
+            Err(err) => {
+                logging::warn!("discarding stored order: {}", err);
+                None
+            }
+        }
+    }
+    async fn store_order_url(
+        config: &AcmeConfig<EC, EA>,
+        directory_url: &str,
+        order_url: Option<&str>,
+    ) {
+        let result = config
+            .cache
+            .store_order(&config.domains, directory_url, order_url)
+            .await;
+        if let Err(err) = result {
+            logging::warn!("could not store order: {:?}", err);
+        }
+    }
+    #[allow(clippy::too_many_arguments)]
+    async fn complete_order(
+        config: &AcmeConfig<EC, EA>,
+        client: &reqwest::Client,
+        resolver: &ResolvesServerCertAcme,
+        account: &Account,
+        key_pair: &rcgen::KeyPair,
+        params: &CertificateParams,
+        progress: &OrderProgress,
+        order_url: &str,
+        mut order: Order,
+    ) -> Result<(SecretBytes, String), OrderError> {
+        let set_phase = |p| *progress.phase.lock().unwrap() = p;
+        // Some CAs answer the finalize request before moving the order out of ready.
+        let mut finalized = false;
+        let mut refetched = false;
         loop {
+            if let Some(expires) = order.expires {
+                if expires - config.clock.now() < chrono::Duration::minutes(5) {
+                    logging::warn!("order expires soon, at {}", expires);
+                }
+            }
             match order.status {
                 OrderStatus::Pending => {
-                    let auth_futures = order
+                    // Collected first, as the lazy iterator makes the order future not Send.
+                    let auth_futures: Vec<_> = order
                         .authorizations
                         .iter()
-                        .map(|url| Self::authorize(&config, &resolver, &account, url));
-                    try_join_all(auth_futures).await?;
-                    log::info!("completed all authorizations");
-                    order = account.order(&config.client_config, &order_url).await?;
+                        .map(|url| {
+                            let auth =
+                                Self::authorize(config, client, resolver, account, url, progress);
+                            #[cfg(feature = "tracing")]
+                            let auth = tracing::Instrument::instrument(
+                                auth,
+                                tracing::info_span!("authorization", url = %url),
+                            );
+                            auth
+                        })
+                        .collect();
+                    futures::stream::iter(auth_futures)
+                        .buffer_unordered(config.max_concurrent_authorizations)
+                        .try_collect::<Vec<()>>()
+                        .await?;
+                    logging::info!("completed all authorizations");
+                    set_phase(AcmePhase::Ordering);
+                    order = account.order(client, &order_url).await?;
+                    if order.status == OrderStatus::Pending {
+                        // An authorization skipped as valid may have been deactivated since.
+                        let mut valid_auths = progress.valid_auths.lock().unwrap();
+                        for url in &order.authorizations {
+                            valid_auths.remove(url);
+                        }
+                    }
                 }
-                OrderStatus::Processing => {
-                    for i in 0u64..10 {
-                        log::info!("order processing");
-                        after(Duration::from_secs(1u64 << i)).await;
-                        order = account.order(&config.client_config, &order_url).await?;
-                        if order.status != OrderStatus::Processing {
+                OrderStatus::Ready if !finalized => {
+                    logging::info!("sending csr");
+                    set_phase(AcmePhase::Finalizing);
+                    let csr = params.serialize_request(key_pair)?;
+                    progress.request_counts.lock().unwrap().finalizations += 1;
+                    if config.max_issuances.is_some() {
+                        let now = config.clock.now();
+                        progress.finalized_at.lock().unwrap().push_back(now);
+                    }
+                    order = account
+                        .finalize(client, order.finalize, csr.der().to_vec())
+                        .await
+                        .map_err(finalize_error)?;
+                    if let Some(problem) = order.error.take() {
+                        return Err(OrderError::FinalizeFailed(problem));
+                    }
+                    finalized = true;
+                }
+                OrderStatus::Processing | OrderStatus::Ready => {
+                    let processing = |status: &OrderStatus| {
+                        *status == OrderStatus::Processing
+                            || (finalized && *status == OrderStatus::Ready)
+                    };
+                    let (attempts, base_delay) = config.processing_poll;
+                    for i in 0..attempts {
+                        logging::info!("order processing");
+                        after(poll_delay(base_delay, i)).await;
+                        order = account.order(client, &order_url).await?;
+                        if !processing(&order.status) {
                             break;
                         }
                     }
-                    if order.status == OrderStatus::Processing {
+                    if processing(&order.status) {
                         return Err(OrderError::ProcessingTimeout(order));
                     }
                 }
-                OrderStatus::Ready => {
-                    log::info!("sending csr");
-                    let csr = params.serialize_request(&key_pair)?;
-                    order = account
-                        .finalize(&config.client_config, order.finalize, csr.der().to_vec())
-                        .await?
+                OrderStatus::Valid { certificate: None } if !refetched => {
+                    logging::warn!("valid order has no certificate url, fetching it again");
+                    refetched = true;
+                    order = account.order(client, &order_url).await?;
+                }
+                OrderStatus::Valid { certificate: None } => {
+                    return Err(OrderError::BadOrder(order));
                 }
-                OrderStatus::Valid { certificate } => {
-                    log::info!("download certificate");
-                    let pem = [
-                        &key_pair.serialize_pem(),
-                        "\n",
-                        &account
-                            .certificate(&config.client_config, certificate)
-                            .await?,
-                    ]
-                    .concat();
-                    return Ok(pem.into_bytes());
+                OrderStatus::Valid {
+                    certificate: Some(certificate),
+                } => {
+                    logging::info!("download certificate");
+                    set_phase(AcmePhase::Finalizing);
+                    let key_pem = SecretBytes::from(key_pair.serialize_pem().into_bytes());
+                    let mut attempt = 0;
+                    let (pem, cert) = loop {
+                        let chain = account.certificate(client, &certificate).await?;
+                        let pem =
+                            SecretBytes::from([&key_pem, &b"\n"[..], chain.as_bytes()].concat());
+                        match Self::parse_cert(&config.crypto_provider, &pem) {
+                            Ok((cert, _)) => break (pem, cert),
+                            Err(err) if attempt + 1 < CERT_DOWNLOAD_ATTEMPTS => {
+                                logging::warn!(
+                                    "downloaded certificate is invalid, retrying: {}",
+                                    err
+                                );
+                                attempt += 1;
+                                after(CERT_DOWNLOAD_DELAY).await;
+                            }
+                            Err(err) => return Err(OrderError::BadCertificate(err)),
+                        }
+                    };
+                    if let Some(hook) = &config.pre_deploy_hook {
+                        hook.pre_deploy(&cert, &pem)
+                            .await
+                            .map_err(OrderError::PreDeployHook)?;
+                    }
+                    return Ok((pem, certificate));
                 }
                 OrderStatus::Invalid => return Err(OrderError::BadOrder(order)),
             }
         }
     }
+    /// The account for `key_pair` at `directory_url`, registering it if necessary.
+    async fn account(
+        config: &AcmeConfig<EC, EA>,
+        client: &reqwest::Client,
+        directory_url: &str,
+        key_pair: &[u8],
+    ) -> Result<Arc<Account>, AcmeError> {
+        match &config.shared_account {
+            Some(shared) => {
+                shared
+                    .account(
+                        client,
+                        directory_url,
+                        &config.contact,
+                        key_pair,
+                        config.agree_tos,
+                        config.debug_log_requests,
+                    )
+                    .await
+            }
+            None => {
+                let mut directory = Directory::discover(client, directory_url).await?;
+                directory.log_requests = config.debug_log_requests;
+                let account = Account::create_with_keypair_and_tos(
+                    client,
+                    directory,
+                    &config.contact,
+                    key_pair,
+                    config.agree_tos,
+                )
+                .await?;
+                Ok(Arc::new(account))
+            }
+        }
+    }
     async fn authorize(
         config: &AcmeConfig<EC, EA>,
+        client: &reqwest::Client,
         resolver: &ResolvesServerCertAcme,
         account: &Account,
         url: &String,
+        progress: &OrderProgress,
     ) -> Result<(), OrderError> {
-        let auth = account.auth(&config.client_config, url).await?;
+        let result = Self::authorize_inner(config, client, resolver, account, url, progress).await;
+        progress
+            .challenges
+            .lock()
+            .unwrap()
+            .retain(|(u, _)| u != url);
+        result
+    }
+    async fn authorize_inner(
+        config: &AcmeConfig<EC, EA>,
+        client: &reqwest::Client,
+        resolver: &ResolvesServerCertAcme,
+        account: &Account,
+        url: &String,
+        progress: &OrderProgress,
+    ) -> Result<(), OrderError> {
+        let valid = progress.valid_auths.lock().unwrap().get(url).cloned();
+        if let Some((domain, expires)) = valid {
+            if expires - config.clock.now() > VALID_AUTH_MARGIN {
+                logging::info!("reusing valid authorization for {}", domain);
+                config.metrics.authorization_completed(&domain);
+                return Ok(());
+            }
+            progress.valid_auths.lock().unwrap().remove(url);
+        }
+        let auth = account.auth(client, url).await?;
         let (domain, challenge_url) = match auth.status {
             AuthStatus::Pending => {
                 let Identifier::Dns(domain) = auth.identifier;
-                log::info!("trigger challenge for {}", &domain);
-                let (challenge, auth_key) =
-                    account.tls_alpn_01(&auth.challenges, domain.clone())?;
-                resolver.set_auth_key(domain.clone(), Arc::new(auth_key));
-                account
-                    .challenge(&config.client_config, &challenge.url)
-                    .await?;
+                logging::info!(
+                    "trigger challenge for {}",
+                    idna::domain_to_unicode(&domain).0
+                );
+                *progress.phase.lock().unwrap() = AcmePhase::Authorizing(domain.clone());
+                let (challenge_type, hook) = match &config.challenge_hook {
+                    Some((challenge_type, hook)) => (*challenge_type, Some(hook)),
+                    None => (ChallengeType::TlsAlpn01, None),
+                };
+                let challenge = match challenge_type {
+                    ChallengeType::TlsAlpn01 => {
+                        let challenge = auth
+                            .challenges
+                            .iter()
+                            .find(|c| c.typ == ChallengeType::TlsAlpn01)
+                            .ok_or(AcmeError::NoTlsAlpn01Challenge)?;
+                        let key = match &config.challenge_key {
+                            Some(key) => key.clone(),
+                            None => resolver.challenge_key()?,
+                        };
+                        let mut params = CertificateParams::new(vec![domain.clone()])?;
+                        if let Some(customize) = &config.challenge_cert_params {
+                            customize(&mut params);
+                        }
+                        let auth_key = account.tls_alpn_01_cert(challenge, params, &key)?;
+                        resolver.set_auth_key(domain.clone(), Arc::new(auth_key));
+                        challenge
+                    }
+                    typ => auth
+                        .challenges
+                        .iter()
+                        .find(|c| c.typ == typ)
+                        .ok_or(AcmeError::NoChallenge(typ))?,
+                };
+                let response = ChallengeResponse {
+                    domain: domain.clone(),
+                    token: challenge.token.clone(),
+                    key_authorization: account.key_authorization(&challenge.token)?,
+                    challenge_type,
+                };
+                let pending = (url.clone(), response.clone());
+                progress.challenges.lock().unwrap().push(pending);
+                if let Some(hook) = hook {
+                    hook.challenge_ready(&response).await;
+                }
+                account.challenge(client, &challenge.url).await?;
                 (domain, challenge.url.clone())
             }
-            AuthStatus::Valid => return Ok(()),
-            _ => return Err(OrderError::BadAuth(auth)),
+            AuthStatus::Valid => {
+                let Identifier::Dns(domain) = &auth.identifier;
+                Self::remember_valid_auth(progress, url, domain, &auth);
+                config.metrics.authorization_completed(domain);
+                return Ok(());
+            }
+            _ => {
+                let Identifier::Dns(domain) = auth.identifier.clone();
+                return Err(OrderError::BadAuth { domain, auth });
+            }
         };
-        for i in 0u64..5 {
-            after(Duration::from_secs(1u64 << i)).await;
-            let auth = account.auth(&config.client_config, url).await?;
+        let (attempts, base_delay) = config.authorization_poll;
+        for i in 0..attempts {
+            after(poll_delay(base_delay, i)).await;
+            let auth = account.auth(client, url).await?;
             match auth.status {
                 AuthStatus::Pending => {
-                    log::info!("authorization for {} still pending", &domain);
-                    account
-                        .challenge(&config.client_config, &challenge_url)
-                        .await?
+                    logging::info!(
+                        "authorization for {} still pending",
+                        idna::domain_to_unicode(&domain).0
+                    );
+                    account.challenge(client, &challenge_url).await?
+                }
+                AuthStatus::Valid => {
+                    Self::remember_valid_auth(progress, url, &domain, &auth);
+                    config.metrics.authorization_completed(&domain);
+                    return Ok(());
                 }
-                AuthStatus::Valid => return Ok(()),
-                _ => return Err(OrderError::BadAuth(auth)),
+                _ => return Err(OrderError::BadAuth { domain, auth }),
             }
         }
         Err(OrderError::TooManyAttemptsAuth(domain))
     }
+    /// Remember a valid authorization, so later orders listing it skip fetching it again.
+    fn remember_valid_auth(progress: &OrderProgress, url: &str, domain: &str, auth: &Auth) {
+        if let Some(expires) = auth.expires {
+            let entry = (domain.to_string(), expires);
+            progress
+                .valid_auths
+                .lock()
+                .unwrap()
+                .insert(url.into(), entry);
+        }
+    }
+    /// Load the certificate and account key from the cache and deploy the certificate.
+    ///
+    /// Call this before accepting connections to avoid handshakes racing the cache load on
+    /// startup. Cache errors are returned instead of being produced as events. The state must still
+    /// be polled afterwards to order and renew certificates.
+    pub async fn preload(&mut self) -> Result<(), EventError<EC, EA>> {
+        if let Some(err) = self.config_error.take() {
+            self.terminate();
+            return Err(EventError::Config(err));
+        }
+        while let Some(event) = futures::future::poll_fn(|cx| self.poll_load(cx)).await {
+            event?;
+        }
+        Ok(())
+    }
+    /// Stop the state machine, but first drive an in-flight order to completion.
+    ///
+    /// Resolves to the events produced while doing so, including the deployment and caching of
+    /// the new certificate. Gives up on the order once `timeout` has elapsed. Note that the order
+    /// can only complete if validation requests are still answered, see [Incoming::shutdown].
+    pub async fn shutdown(mut self, timeout: Duration) -> Vec<Event<EC, EA>> {
+        let mut events = Vec::new();
+        let drain = async {
+            while self.has_pending_work() {
+                match self.next().await {
+                    Some(event) => events.push(event),
+                    None => break,
+                }
+            }
+        };
+        if tokio::time::timeout(timeout, drain).await.is_err() {
+            logging::warn!("shutdown timed out, abandoning the order in flight");
+        }
+        events
+    }
+    /// The events of the state machine, for merging into a select loop.
+    ///
+    /// This is the state itself, as it is a [Stream] of events. It must be polled continuously for
+    /// the certificate to be renewed.
+    pub fn drive(self) -> impl FusedStream<Item = Event<EC, EA>> + Unpin {
+        self
+    }
+    /// Drive the state machine on a spawned task, sending its events to the returned channel.
+    ///
+    /// Unlike the rest of the crate this spawns a task, which runs until the state terminates or
+    /// the [JoinHandle](tokio::task::JoinHandle) is aborted. Events are dropped while the channel
+    /// is full or after the receiver is dropped, so a slow consumer never delays renewals.
+    #[cfg(feature = "driver")]
+    pub fn spawn_driver(
+        mut self,
+        capacity: usize,
+    ) -> (
+        tokio::task::JoinHandle<()>,
+        tokio::sync::mpsc::Receiver<Event<EC, EA>>,
+    )
+    where
+        EC: Send,
+        EA: Send,
+    {
+        let (sender, receiver) = tokio::sync::mpsc::channel(capacity);
+        let handle = tokio::spawn(async move {
+            while let Some(event) = self.next().await {
+                if let Err(tokio::sync::mpsc::error::TrySendError::Full(event)) =
+                    sender.try_send(event)
+                {
+                    logging::warn!("event channel is full, dropping event: {:?}", event);
+                }
+            }
+        });
+        (handle, receiver)
+    }
+    /// Obtain a certificate once, without serving connections.
+    ///
+    /// Like [AcmeState::bootstrap], but consumes the state and resolves to the certificate and
+    /// its not-before and not-after.
+    pub async fn obtain_certificate<
+        TCP: AsyncRead + AsyncWrite + Unpin,
+        ETCP,
+        ITCP: Stream<Item = Result<TCP, ETCP>> + Unpin,
+    >(
+        mut self,
+        tcp_incoming: ITCP,
+    ) -> Result<(Arc<CertifiedKey>, [DateTime<Utc>; 2]), ObtainError<EC, EA>> {
+        self.bootstrap(tcp_incoming).await?;
+        let resolver = self.resolver();
+        match (resolver.certified_key(), resolver.validity()) {
+            (Some(cert), Some(validity)) => Ok((cert, validity)),
+            _ => Err(ObtainError::Terminated),
+        }
+    }
+    /// Deploy a first certificate before serving any traffic.
+    ///
+    /// Deploys the cached certificate or runs an order, answering TLS-ALPN-01 validation requests
+    /// arriving on `tcp_incoming` and closing all other connections. A temporary listener, or the
+    /// real one before traffic is routed to it, can be used. Resolves once the certificate is
+    /// deployed and stored in the cache. The first error aborts, errors after the deployment, like
+    /// a failed cache store, are only logged.
+    ///
+    /// Afterwards the state serves connections as usual, e.g. with [AcmeState::incoming], and
+    /// renews the certificate from there. Clients thus never see a handshake failing for lack of
+    /// a certificate.
+    pub async fn bootstrap<
+        TCP: AsyncRead + AsyncWrite + Unpin,
+        ETCP,
+        ITCP: Stream<Item = Result<TCP, ETCP>> + Unpin,
+    >(
+        &mut self,
+        tcp_incoming: ITCP,
+    ) -> Result<(), ObtainError<EC, EA>> {
+        let acceptor = self.acceptor();
+        let mut tcp_incoming = Some(tcp_incoming);
+        let mut accepting = futures::stream::FuturesUnordered::new();
+        let mut deployed = false;
+        futures::future::poll_fn(|cx| loop {
+            if deployed && !self.has_pending_work() {
+                return Poll::Ready(Ok(()));
+            }
+            match Pin::new(&mut *self).poll_next(cx) {
+                Poll::Ready(Some(Ok(
+                    ok @ (EventOk::DeployedCachedCert | EventOk::DeployedNewCert { .. }),
+                ))) => {
+                    logging::info!("event: {:?}", ok);
+                    deployed = true;
+                    continue;
+                }
+                Poll::Ready(Some(Ok(ok))) => {
+                    logging::info!("event: {:?}", ok);
+                    continue;
+                }
+                Poll::Ready(Some(Err(err))) if deployed => {
+                    logging::warn!("event: {:?}", err);
+                    continue;
+                }
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Err(err.into())),
+                Poll::Ready(None) if deployed => return Poll::Ready(Ok(())),
+                Poll::Ready(None) => return Poll::Ready(Err(ObtainError::Terminated)),
+                Poll::Pending => {}
+            }
+            while let Poll::Ready(Some(accepted)) = Pin::new(&mut accepting).poll_next(cx) {
+                match accepted {
+                    Ok(AcceptedOrValidation::Validation) => {
+                        logging::info!("received TLS-ALPN-01 validation request")
+                    }
+                    Ok(AcceptedOrValidation::Accepted(_)) => {}
+                    Err(err) => logging::error!("tls accept failed, {:?}", err),
+                }
+            }
+            let incoming = match &mut tcp_incoming {
+                Some(incoming) => incoming,
+                None => return Poll::Pending,
+            };
+            match Pin::new(incoming).poll_next(cx) {
+                Poll::Ready(Some(Ok(tcp))) => accepting.push(acceptor.accept(tcp)),
+                Poll::Ready(Some(Err(_))) => logging::error!("tcp accept failed"),
+                Poll::Ready(None) => tcp_incoming = None,
+                Poll::Pending => return Poll::Pending,
+            }
+        })
+        .await
+    }
+    /// Whether an order or a cache store is in flight.
+    pub(crate) fn has_pending_work(&self) -> bool {
+        !self.terminated
+            && (self.order.is_some()
+                || self.early_action.is_some()
+                || self.revocation.is_some()
+                || self.discard_cached_cert.is_some())
+    }
+    /// Stop the state machine, dropping all in-flight futures.
+    fn terminate(&mut self) {
+        self.terminated = true;
+        self.early_action = None;
+        self.revocation = None;
+        self.discard_cached_cert = None;
+        self.load_cert = None;
+        self.loaded_cert = None;
+        self.load_account = None;
+        self.order = None;
+        self.wait = None;
+        self.progress.challenges.lock().unwrap().clear();
+    }
+    #[cfg(feature = "tokio-util")]
+    fn poll_cancelled(&mut self, cx: &mut Context<'_>) -> bool {
+        match &mut self.cancelled {
+            Some(cancelled) => cancelled.poll_unpin(cx).is_ready(),
+            None => false,
+        }
+    }
+    #[cfg(not(feature = "tokio-util"))]
+    fn poll_cancelled(&mut self, _cx: &mut Context<'_>) -> bool {
+        false
+    }
+    /// Load the certificate and account key from the cache, if not done yet. Resolves to `None`
+    /// once both are loaded.
+    fn poll_load(&mut self, cx: &mut Context<'_>) -> Poll<Option<Event<EC, EA>>> {
+        // load from cert cache
+        if let Some(load_cert) = &mut self.load_cert {
+            let (result, elapsed) = ready!(load_cert.poll_unpin(cx));
+            self.load_cert.take();
+            let hit = match result {
+                Ok(Some((directory, entry))) => {
+                    self.loaded_cert = Some((directory, SecretBytes::from(entry)));
+                    true
+                }
+                Ok(None) => false,
+                Err(err) => return Poll::Ready(Some(Err(EventError::CertCacheLoad(err)))),
+            };
+            return Poll::Ready(Some(Ok(EventOk::CertCacheLoad { hit, elapsed })));
+        }
+        if let Some((directory, entry)) = self.loaded_cert.take() {
+            self.directory = directory;
+            let pem = match decode_cache_entry(&entry) {
+                Ok(pem) => SecretBytes::from(pem.to_vec()),
+                Err(err) => return Poll::Ready(Some(Err(EventError::CachedCertParse(err.into())))),
+            };
+            return Poll::Ready(Some(self.process_cert(pem, None)));
+        }
+
+        // load from account cache
+        if let Some(load_account) = &mut self.load_account {
+            let (result, elapsed) = ready!(load_account.poll_unpin(cx));
+            self.load_account.take();
+            let hit = match result {
+                Ok(Some(entry)) => {
+                    let entry = SecretBytes::from(entry);
+                    let key_pair = match decode_cache_entry(&entry) {
+                        Ok(key_pair) => SecretBytes::from(key_pair.to_vec()),
+                        Err(err) => {
+                            return Poll::Ready(Some(Err(EventError::CachedAccountParse(err))))
+                        }
+                    };
+                    self.account_key = Some(match &self.config.shared_account {
+                        Some(shared) => shared.key_pair_or_insert_with(|| key_pair).0,
+                        None => key_pair,
+                    });
+                    true
+                }
+                Ok(None) => false,
+                Err(err) => return Poll::Ready(Some(Err(EventError::AccountCacheLoad(err)))),
+            };
+            return Poll::Ready(Some(Ok(EventOk::AccountCacheLoad { hit, elapsed })));
+        }
+        Poll::Ready(None)
+    }
     fn poll_next_infinite(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Event<EC, EA>> {
         loop {
             // queued early action
@@ -332,33 +1553,37 @@ impl<EC: 'static + Debug, EA: 'static + Debug> AcmeState<EC, EA> {
                 return Poll::Ready(result);
             }
 
+            // discard the cached certificate before revoking it
+            if let Some(discard) = &mut self.discard_cached_cert {
+                let result = ready!(discard.poll_unpin(cx));
+                self.discard_cached_cert.take();
+                return Poll::Ready(result);
+            }
+
+            // revocation before reissuing
+            if let Some(revocation) = &mut self.revocation {
+                let result = ready!(revocation.poll_unpin(cx));
+                self.revocation.take();
+                return Poll::Ready(result);
+            }
+
             // sleep
             if let Some(timer) = &mut self.wait {
                 ready!(timer.poll_unpin(cx));
                 self.wait.take();
             }
 
-            // load from cert cache
-            if let Some(load_cert) = &mut self.load_cert {
-                let result = ready!(load_cert.poll_unpin(cx));
-                self.load_cert.take();
-                match result {
-                    Ok(Some(pem)) => {
-                        return Poll::Ready(Self::process_cert(self.get_mut(), pem, true));
-                    }
-                    Ok(None) => {}
-                    Err(err) => return Poll::Ready(Err(EventError::CertCacheLoad(err))),
-                }
+            // load from cert and account cache
+            if let Some(event) = ready!(self.poll_load(cx)) {
+                return Poll::Ready(event);
             }
 
-            // load from account cache
-            if let Some(load_account) = &mut self.load_account {
-                let result = ready!(load_account.poll_unpin(cx));
-                self.load_account.take();
-                match result {
-                    Ok(Some(key_pair)) => self.account_key = Some(key_pair),
-                    Ok(None) => {}
-                    Err(err) => return Poll::Ready(Err(EventError::AccountCacheLoad(err))),
+            // report a new account url
+            if let Some(url) = self.account_url() {
+                if self.reported_account_url.as_ref() != Some(&url) {
+                    logging::info!("using account {}", url);
+                    self.reported_account_url = Some(url.clone());
+                    return Poll::Ready(Ok(EventOk::AccountUrl { url }));
                 }
             }
 
@@ -366,50 +1591,141 @@ impl<EC: 'static + Debug, EA: 'static + Debug> AcmeState<EC, EA> {
             if let Some(order) = &mut self.order {
                 let result = ready!(order.poll_unpin(cx));
                 self.order.take();
+                self.progress.challenges.lock().unwrap().clear();
                 match result {
-                    Ok(pem) => {
+                    Ok((pem, certificate_url)) => {
+                        self.config.metrics.order_succeeded();
                         self.backoff_cnt = 0;
-                        return Poll::Ready(Self::process_cert(self.get_mut(), pem, false));
+                        self.failed_orders = 0;
+                        self.failovers = 0;
+                        return Poll::Ready(Self::process_cert(
+                            self.get_mut(),
+                            pem,
+                            Some(certificate_url),
+                        ));
                     }
                     Err(err) => {
+                        self.config.metrics.order_failed(&err);
+                        self.failed_orders += 1;
+                        if let Some(max) = self.config.max_order_attempts {
+                            if self.failed_orders >= max {
+                                let attempts = self.failed_orders;
+                                self.terminate();
+                                return Poll::Ready(Err(EventError::OrdersExhausted {
+                                    attempts,
+                                    last: err,
+                                }));
+                            }
+                        }
                         // TODO: replace key on some errors or high backoff_cnt?
-                        self.wait = Some(after(Duration::from_secs(1 << self.backoff_cnt)));
-                        self.backoff_cnt = (self.backoff_cnt + 1).min(16);
+                        let directories = self.config.directory_urls().count();
+                        self.directory = (self.directory + 1) % directories;
+                        self.failovers += 1;
+                        let delay = match err.retry_after() {
+                            _ if self.failovers < directories => TRANSIENT_RETRY_DELAY,
+                            Some(retry_after) => retry_after,
+                            None if err.is_transient() => TRANSIENT_RETRY_DELAY,
+                            None => {
+                                let delay = Duration::from_secs(1 << self.backoff_cnt);
+                                self.backoff_cnt = (self.backoff_cnt + 1).min(16);
+                                delay
+                            }
+                        };
+                        if self.failovers >= directories {
+                            self.failovers = 0;
+                        }
+                        self.wait = Some(after(delay));
                         return Poll::Ready(Err(EventError::Order(err)));
                     }
                 }
             }
 
+            // local cap on issuances
+            if let Some(retry_at) = self.issuance_cap_reached() {
+                let (window, count) = self.config.max_issuances.unwrap();
+                let delay = (retry_at - self.config.clock.now())
+                    .to_std()
+                    .unwrap_or_default();
+                logging::warn!("issuance cap reached, next order at {}", retry_at);
+                self.wait = Some(after(delay));
+                return Poll::Ready(Err(EventError::IssuanceCapReached {
+                    count,
+                    window,
+                    retry_at,
+                }));
+            }
+
             // schedule order
             let account_key = match &self.account_key {
                 None => {
-                    let account_key = Account::generate_key_pair();
+                    let alg = self.config.account_key_alg;
+                    let generate = || Account::generate_key_pair_with_alg(alg);
+                    let (account_key, generated) = match &self.config.shared_account {
+                        Some(shared) => shared.key_pair_or_insert_with(generate),
+                        None => (generate(), true),
+                    };
                     self.account_key = Some(account_key.clone());
-                    let config = self.config.clone();
-                    let account_key_clone = account_key.clone();
-                    self.early_action = Some(Box::pin(async move {
-                        match config
-                            .cache
-                            .store_account(
-                                &config.contact,
-                                &config.directory_url,
-                                &account_key_clone,
-                            )
-                            .await
-                        {
-                            Ok(()) => Ok(EventOk::AccountCacheStore),
-                            Err(err) => Err(EventError::AccountCacheStore(err)),
-                        }
-                    }));
+                    if generated {
+                        let config = self.config.clone();
+                        let account_key_clone = account_key.clone();
+                        self.early_action = Some(Box::pin(async move {
+                            let entry = SecretBytes::from(encode_cache_entry(&account_key_clone));
+                            let store = || {
+                                config.cache.store_account(
+                                    &config.contact,
+                                    &config.directory_url,
+                                    &entry,
+                                )
+                            };
+                            let retries = config.cache_store_retries;
+                            match store_with_retries("account key", retries, store).await {
+                                Ok(()) => Ok(EventOk::AccountCacheStore),
+                                Err(err) => Err(EventError::AccountCacheStore(err)),
+                            }
+                        }));
+                    }
                     account_key
                 }
                 Some(account_key) => account_key.clone(),
             };
             let config = self.config.clone();
             let resolver = self.resolver.clone();
-            self.order = Some(Box::pin({
-                Self::order(config.clone(), resolver.clone(), account_key)
-            }));
+            let progress = self.progress.clone();
+            let directory_url = self.directory_url().to_string();
+            let cert_key = match self.discard_cert_key {
+                true => None,
+                false => config.cert_key.clone(),
+            };
+            config
+                .metrics
+                .order_started(&config.domains, &directory_url, self.backoff_cnt);
+            let order = Self::order(
+                config.clone(),
+                resolver.clone(),
+                directory_url.clone(),
+                account_key,
+                cert_key,
+                progress,
+            );
+            let deadline = config.order_deadline;
+            let order = async move {
+                match deadline {
+                    Some(deadline) => tokio::time::timeout(deadline, order)
+                        .await
+                        .unwrap_or(Err(OrderError::DeadlineExceeded(deadline))),
+                    None => order.await,
+                }
+            };
+            #[cfg(feature = "tracing")]
+            let order = tracing::Instrument::instrument(
+                order,
+                tracing::info_span!(
+                    "order",
+                    domains = ?config.domains,
+                    directory = %directory_url,
+                ),
+            );
+            self.order = Some(Box::pin(order));
         }
     }
 }
@@ -417,7 +1733,360 @@ impl<EC: 'static + Debug, EA: 'static + Debug> AcmeState<EC, EA> {
 impl<EC: 'static + Debug, EA: 'static + Debug> Stream for AcmeState<EC, EA> {
     type Item = Event<EC, EA>;
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.terminated {
+            return Poll::Ready(None);
+        }
+        if let Some(err) = self.config_error.take() {
+            self.terminate();
+            return Poll::Ready(Some(Err(EventError::Config(err))));
+        }
+        if self.poll_cancelled(cx) {
+            self.terminate();
+            return Poll::Ready(None);
+        }
         Poll::Ready(Some(ready!(self.poll_next_infinite(cx))))
     }
 }
+
+impl<EC: 'static + Debug, EA: 'static + Debug> FusedStream for AcmeState<EC, EA> {
+    fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caches::{DirCache, NoCache, TestCache};
+    use crate::mock_acme::{Finalize, MockAcme, MockOptions};
+    use crate::{AcmeConfig, CertCache, Clock};
+    use futures::StreamExt;
+
+    fn mock_config(mock: &MockAcme) -> AcmeConfig<Infallible> {
+        AcmeConfig::new(["example.com"])
+            .directory(mock.directory_url())
+            .cert_key(mock.cert_key())
+            .unwrap()
+    }
+
+    /// Run `state` until it deployed a new certificate, and return its url.
+    async fn deploy<EC: 'static + Debug, EA: 'static + Debug>(
+        state: &mut AcmeState<EC, EA>,
+    ) -> String {
+        let deploy = async {
+            loop {
+                match state.next().await.unwrap() {
+                    Ok(EventOk::DeployedNewCert { certificate_url }) => return certificate_url,
+                    Ok(_) => {}
+                    Err(err) => panic!("order failed: {:?}", err),
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), deploy)
+            .await
+            .unwrap()
+    }
+
+    fn order_fetches(mock: &MockAcme) -> usize {
+        let requests = mock.requests().into_iter();
+        requests.filter(|request| request.path == "/order").count()
+    }
+
+    #[tokio::test]
+    async fn production_with_no_cache_is_rejected() {
+        let mut state = AcmeConfig::new(["example.com"])
+            .directory_lets_encrypt(true)
+            .cache(NoCache::<std::io::Error>::new())
+            .state();
+        assert!(matches!(
+            state.next().await,
+            Some(Err(EventError::Config(ConfigError::NoCacheInProduction)))
+        ));
+        assert!(state.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn rotate_on_compromise_discards_the_cached_cert() {
+        let dir = std::env::temp_dir().join(format!("rotate-{}", std::process::id()));
+        let cache = Arc::new(DirCache::new(dir.clone()));
+        let domains = vec!["example.com".to_string()];
+        let config = AcmeConfig::new(domains.clone()).cache(cache.clone());
+        let directory_url = config.directory_urls().next().unwrap().to_string();
+        cache
+            .store_cert(&domains, &directory_url, b"revoked")
+            .await
+            .unwrap();
+
+        let mut state = config.state();
+        state.rotate_on_compromise();
+        assert!(matches!(
+            state.next().await,
+            Some(Ok(EventOk::CachedCertDiscarded))
+        ));
+        let cert = cache.load_cert(&domains, &directory_url).await.unwrap();
+        assert_eq!(cert, None);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn finalize_answering_valid_deploys_the_certificate() {
+        let mock = MockAcme::start(MockOptions::default()).await;
+        let mut state = mock_config(&mock).state();
+        deploy(&mut state).await;
+        assert_eq!(order_fetches(&mock), 0);
+    }
+
+    #[tokio::test]
+    async fn valid_order_without_certificate_url_is_fetched_again() {
+        let mock = MockAcme::start(MockOptions {
+            finalize: Finalize::ValidWithoutCertificate,
+            ..Default::default()
+        })
+        .await;
+        let mut state = mock_config(&mock).state();
+        deploy(&mut state).await;
+        assert_eq!(order_fetches(&mock), 1);
+    }
+
+    #[tokio::test]
+    async fn order_in_flight_is_resumed_from_an_imported_archive() {
+        let mock = MockAcme::start(MockOptions::default()).await;
+        let (from, to) = (
+            std::env::temp_dir().join(format!("resume-from-{}", std::process::id())),
+            std::env::temp_dir().join(format!("resume-to-{}", std::process::id())),
+        );
+        let domains = vec!["example.com".to_string()];
+        let source = DirCache::new(from.clone());
+        source
+            .store_order(&domains, &mock.directory_url(), Some(&mock.order_url()))
+            .await
+            .unwrap();
+        let mut archive = Vec::new();
+        crate::caches::archive::export(&source, &mut archive)
+            .await
+            .unwrap();
+        let target = DirCache::new(to.clone());
+        crate::caches::archive::import(&target, archive.as_slice())
+            .await
+            .unwrap();
+
+        let config = mock_config(&mock).cache(target).resume_orders(true);
+        let mut state = config.state();
+        deploy(&mut state).await;
+        assert!(!mock.requested("/new-order"));
+        assert_eq!(order_fetches(&mock), 1);
+        std::fs::remove_dir_all(from).unwrap();
+        std::fs::remove_dir_all(to).unwrap();
+    }
+
+    #[cfg(feature = "tokio-util")]
+    #[tokio::test]
+    async fn cancellation_drops_the_order_in_flight() {
+        // The directory request is accepted but never answered, so the order stays in flight.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let directory_url = format!("http://{}/directory", listener.local_addr().unwrap());
+        let token = tokio_util::sync::CancellationToken::new();
+        let mut state = AcmeConfig::new(["example.com"])
+            .directory(directory_url)
+            .cancellation_token(token.clone())
+            .state();
+        let _connection = loop {
+            tokio::select! {
+                accepted = listener.accept() => break accepted.unwrap(),
+                _ = state.next() => {}
+            }
+        };
+        assert!(state.order.is_some());
+
+        token.cancel();
+        let next = tokio::time::timeout(Duration::from_secs(1), state.next());
+        assert!(next.await.unwrap().is_none());
+        assert!(state.order.is_none());
+        assert!(state.is_terminated());
+    }
+
+    #[tokio::test]
+    async fn certificate_url_is_surfaced_after_an_order() {
+        let mock = MockAcme::start(MockOptions::default()).await;
+        let mut state = mock_config(&mock).state();
+        assert_eq!(state.certificate_url(), None);
+        assert_eq!(deploy(&mut state).await, mock.certificate_url());
+        assert_eq!(state.certificate_url(), Some(&*mock.certificate_url()));
+    }
+
+    #[tokio::test]
+    async fn user_agent_is_sent_with_all_requests() {
+        let mock = MockAcme::start(MockOptions::default()).await;
+        let config = mock_config(&mock).user_agent("acme-test/1.0");
+        deploy(&mut config.state()).await;
+        assert!(mock.requested("/finalize"));
+        for request in mock.requests() {
+            assert_eq!(request.user_agent.as_deref(), Some("acme-test/1.0"));
+        }
+    }
+
+    #[tokio::test]
+    async fn renewals_with_cert_key_keep_the_public_key() {
+        use x509_parser::certification_request::X509CertificationRequest;
+        use x509_parser::prelude::FromDer;
+
+        let mock = MockAcme::start(MockOptions::default()).await;
+        let mut state = mock_config(&mock).state();
+        deploy(&mut state).await;
+        state.force_renew();
+        deploy(&mut state).await;
+
+        let csrs = mock.csrs();
+        assert_eq!(csrs.len(), 2);
+        let spkis: Vec<_> = csrs
+            .iter()
+            .map(|csr| {
+                let (_, csr) = X509CertificationRequest::from_der(csr).unwrap();
+                csr.certification_request_info.subject_pki.raw.to_vec()
+            })
+            .collect();
+        assert_eq!(spkis[0], spkis[1]);
+    }
+
+    #[test]
+    fn cert_key_rejects_other_algorithms() {
+        for alg in [&rcgen::PKCS_ECDSA_P384_SHA384, &rcgen::PKCS_ED25519] {
+            let key_pair = rcgen::KeyPair::generate_for(alg).unwrap();
+            let config = AcmeConfig::new(["example.com"]).cert_key(key_pair.serialize_der());
+            assert!(config.is_err());
+        }
+    }
+
+    /// Advance the paused clock to `margin` before `delay` and check that `state` is still waiting,
+    /// then advance to `margin` after it and return the next event.
+    async fn next_event_after<EC: 'static + Debug, EA: 'static + Debug>(
+        state: &mut AcmeState<EC, EA>,
+        delay: Duration,
+        margin: Duration,
+    ) -> Event<EC, EA> {
+        tokio::time::advance(delay - margin).await;
+        assert!(state.next().now_or_never().is_none());
+        tokio::time::advance(margin * 2).await;
+        state.next().await.unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn renewal_starts_after_two_thirds_of_the_lifetime_and_backs_off() {
+        // Orders fail right away, as the directory does not exist.
+        let mock = MockAcme::start(MockOptions::default()).await;
+        let directory_url = mock.directory_url().replace("/directory", "/missing");
+        let lifetime = Duration::from_secs(90 * 24 * 60 * 60);
+        let mut state = AcmeConfig::new(["example.com"])
+            .directory(directory_url)
+            .cache(TestCache::<Infallible>::new().with_validity(lifetime))
+            .state();
+        assert!(matches!(
+            state.next().await,
+            Some(Ok(EventOk::CertCacheLoad { hit: true, .. }))
+        ));
+        assert!(matches!(
+            state.next().await,
+            Some(Ok(EventOk::DeployedCachedCert))
+        ));
+
+        // Certificate validity has a resolution of seconds.
+        let margin = Duration::from_secs(2);
+        let event = next_event_after(&mut state, lifetime * 2 / 3, margin).await;
+        assert!(matches!(
+            event,
+            Ok(EventOk::AccountCacheLoad { hit: false, .. })
+        ));
+        assert!(matches!(
+            state.next().await,
+            Some(Ok(EventOk::AccountCacheStore))
+        ));
+        assert!(state.order.is_some());
+        assert!(matches!(
+            state.next().await,
+            Some(Err(EventError::Order(_)))
+        ));
+        for backoff in [1, 2, 4] {
+            let delay = Duration::from_secs(backoff);
+            let event = next_event_after(&mut state, delay, Duration::from_millis(10)).await;
+            assert!(matches!(event, Err(EventError::Order(_))));
+        }
+    }
+
+    /// A clock ahead of the system clock by the given duration.
+    struct AheadClock(chrono::Duration);
+
+    impl Clock for AheadClock {
+        fn now(&self) -> DateTime<Utc> {
+            Utc::now() + self.0
+        }
+    }
+
+    /// Deploy a certificate valid for 90 days from a [TestCache] to a state whose clock is `age`
+    /// ahead, and return how long it waits before renewing it.
+    async fn renewal_delay(age: chrono::Duration) -> Duration {
+        let validity = Duration::from_secs(90 * 24 * 60 * 60);
+        let mut state = AcmeConfig::new(["example.com"])
+            .cache(TestCache::<Infallible>::new().with_validity(validity))
+            .clock(AheadClock(age))
+            .state();
+        assert!(matches!(
+            state.next().await,
+            Some(Ok(EventOk::CertCacheLoad { hit: true, .. }))
+        ));
+        assert!(matches!(
+            state.next().await,
+            Some(Ok(EventOk::DeployedCachedCert))
+        ));
+        let deadline = state.wait.as_ref().unwrap().deadline();
+        deadline - tokio::time::Instant::now()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn certificate_89_days_old_is_renewed_right_away() {
+        let delay = renewal_delay(chrono::Duration::days(89)).await;
+        assert_eq!(delay, Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn certificate_1_day_old_is_renewed_after_59_days() {
+        let delay = renewal_delay(chrono::Duration::days(1)).await;
+        // Certificate validity has a resolution of seconds.
+        let days_59 = Duration::from_secs(59 * 24 * 60 * 60);
+        assert!(delay <= days_59 && delay > days_59 - Duration::from_secs(2));
+    }
+
+    #[test]
+    fn has_key_finds_the_private_key() {
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let other = rcgen::KeyPair::generate().unwrap();
+        let pem = key_pair.serialize_pem();
+        assert!(AcmeState::<std::io::Error>::has_key(
+            pem.as_bytes(),
+            &key_pair.serialize_der()
+        ));
+        assert!(!AcmeState::<std::io::Error>::has_key(
+            pem.as_bytes(),
+            &other.serialize_der()
+        ));
+    }
+
+    #[tokio::test]
+    async fn requested_validity_is_sent_with_the_order() {
+        let mock = MockAcme::start(MockOptions::default()).await;
+        let valid_for = Duration::from_secs(7 * 24 * 60 * 60);
+        let config = mock_config(&mock).requested_validity(valid_for);
+        deploy(&mut config.state()).await;
+
+        let orders = mock.payloads("/new-order");
+        let parse = |value: &serde_json::Value| {
+            DateTime::parse_from_rfc3339(value.as_str().unwrap()).unwrap()
+        };
+        let (not_before, not_after) = (
+            parse(&orders[0]["notBefore"]),
+            parse(&orders[0]["notAfter"]),
+        );
+        assert_eq!((not_after - not_before).to_std().unwrap(), valid_for);
+    }
+}